@@ -0,0 +1,109 @@
+use crate::database::job::Job;
+use crate::database::SqliteConnection;
+use crate::parser::{parse_workflow, RunOptions};
+use crate::repo::Repo;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tokio::sync::mpsc;
+
+// Options for `phantom_ci exec`
+#[derive(Debug, Clone)]
+pub struct ExecOptions {
+    // Workflow file; defaults to workflow/<branch>.toml, then workflow/main.toml, under `dir`
+    pub workflow: Option<String>,
+    // Local checkout to run in
+    pub dir: String,
+    // Record the run in the database instead of a throwaway in-memory one
+    pub record: bool,
+    // Send webhook notifications
+    pub notify: bool,
+}
+
+// Run a workflow against a local checkout with the same step semantics as the daemon.
+// Only `record` touches the real database. Returns whether the workflow succeeded.
+pub async fn exec_workflow(options: ExecOptions) -> Result<bool, String> {
+    let dir = fs::canonicalize(&options.dir)
+        .map_err(|e| format!("invalid directory {}: {}", options.dir, e))?;
+    let work_dir = dir.to_string_lossy().to_string();
+    let branch = git_output(&dir, &["rev-parse", "--abbrev-ref", "HEAD"])
+        .filter(|b| b != "HEAD")
+        .unwrap_or_else(|| "local".to_string());
+    let sha = git_output(&dir, &["rev-parse", "HEAD"]);
+
+    let workflow = match &options.workflow {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let by_branch = dir.join("workflow").join(format!("{}.toml", branch));
+            if by_branch.exists() {
+                by_branch
+            } else {
+                dir.join("workflow").join("main.toml")
+            }
+        }
+    };
+    if !workflow.exists() {
+        return Err(format!("workflow file {} not found", workflow.display()));
+    }
+
+    let db = if options.record {
+        SqliteConnection::new().map_err(|e| format!("unable to open database: {}", e))?
+    } else {
+        SqliteConnection::open_in_memory()
+            .map_err(|e| format!("unable to open in-memory database: {}", e))?
+    }
+    .into_shared();
+
+    let name = dir
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| work_dir.clone());
+    let repo = Repo::new(name, work_dir.clone(), work_dir, sha.clone(), branch);
+    // parse_workflow updates the job row for this repo/branch
+    if !Job::check_exists(&db, &repo.path, &repo.target_branch) {
+        let mut job = Job {
+            id: 0,
+            repo: repo.path.clone(),
+            status: "idle".to_string(),
+            priority: 0,
+            created_at: "".to_string(),
+            updated_at: "".to_string(),
+            start_time: "".to_string(),
+            finish_time: "".to_string(),
+            error_message: "".to_string(),
+            result: "".to_string(),
+            sha: sha.unwrap_or_default(),
+            target_branch: repo.target_branch.clone(),
+        };
+        job.add_job(&db);
+    }
+    Job::update_start_time(&db, &repo.path, &repo.target_branch);
+    Job::update_status(&db, &repo.path, &repo.target_branch, "running");
+
+    // Output and step results are already printed as they happen; just keep the channel moving
+    let (tx, mut rx) = mpsc::channel::<String>(100);
+    tokio::spawn(async move { while rx.recv().await.is_some() {} });
+
+    let options = RunOptions {
+        notify: options.notify,
+        // Unrecorded runs get throwaway run ids, which would clash with recorded ones
+        log_files: options.record,
+        ..Default::default()
+    };
+    let workflow = workflow.to_string_lossy().to_string();
+    Ok(parse_workflow(&workflow, repo, "exec", &db, tx, options).await)
+}
+
+fn git_output(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
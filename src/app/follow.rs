@@ -0,0 +1,68 @@
+use crate::database::joblog::JobLog;
+use crate::database::run::Run;
+use crate::database::DbConn;
+use chrono::DateTime;
+use std::time::Duration;
+
+// How often the database is checked for new log rows and the run's status
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+// Print the log of the newest running run as the daemon writes it, waiting for one to
+// start if needed. `repo` matches exactly, `repo_contains` as a substring. Returns once
+// the run has finished, with whether it succeeded.
+pub async fn follow_logs(db: &DbConn, repo: Option<&str>, repo_contains: Option<&str>) -> bool {
+    let mut waiting = false;
+    let run = loop {
+        if let Some(run) = Run::get_running(db, repo, repo_contains) {
+            break run;
+        }
+        if !waiting {
+            match repo.or(repo_contains) {
+                Some(repo) => println!("Waiting for a run of {} to start...", repo),
+                None => println!("Waiting for a run to start..."),
+            }
+            waiting = true;
+        }
+        tokio::time::sleep(FOLLOW_INTERVAL).await;
+    };
+
+    let matrix = if run.matrix.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", run.matrix)
+    };
+    println!(
+        "Following run #{} {} [{}] {}{}",
+        run.id,
+        run.repo,
+        run.branch,
+        run.sha.get(..8).unwrap_or(run.sha.as_str()),
+        matrix
+    );
+
+    let mut last_id = 0;
+    loop {
+        // Read the status first: a run's final log rows are written before it is marked finished
+        let status = Run::get_run(db, run.id)
+            .map(|r| r.status)
+            .unwrap_or_default();
+        for log in JobLog::get_logs_after(db, run.id, last_id) {
+            last_id = log.id;
+            print_log(&log);
+        }
+        if status != "running" {
+            println!("Run #{} finished :: {}", run.id, status);
+            return status == "success";
+        }
+        tokio::time::sleep(FOLLOW_INTERVAL).await;
+    }
+}
+
+// Step output is one row per line; for step results only the status line is shown,
+// as their output preview has already been printed line by line
+fn print_log(log: &JobLog) {
+    let time = DateTime::parse_from_rfc3339(&log.logged_at)
+        .map(|t| t.format("%H:%M:%S%.3f").to_string())
+        .unwrap_or_else(|_| log.logged_at.clone());
+    println!("{} {}", time, log.log_message.lines().next().unwrap_or(""));
+}
@@ -1 +1,5 @@
+pub mod exec;
+pub mod follow;
+pub mod pool;
+pub mod prune;
 pub mod state;
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Jobs that run at once across all repos unless Config.toml sets max_concurrent_jobs
+pub const DEFAULT_MAX_CONCURRENT_JOBS: usize = 4;
+// Jobs of one repo that run at once unless its Repo.toml entry sets max_concurrent_jobs
+pub const DEFAULT_REPO_CONCURRENT_JOBS: usize = 1;
+
+// Bounds how many triggered runs execute at once, overall and per repo. Each running job of
+// a repo holds a numbered slot so concurrent jobs can be given their own checkout.
+#[derive(Debug, Clone)]
+pub struct JobPool {
+    global: Arc<Semaphore>,
+    repos: Arc<Mutex<HashMap<String, RepoSlots>>>,
+}
+
+#[derive(Debug, Clone)]
+struct RepoSlots {
+    permits: Arc<Semaphore>,
+    in_use: Arc<Mutex<Vec<bool>>>,
+}
+
+// A claimed place in the pool; dropping it frees the slot for the next job
+#[derive(Debug)]
+pub struct JobSlot {
+    pub slot: usize,
+    in_use: Arc<Mutex<Vec<bool>>>,
+    _repo_permit: OwnedSemaphorePermit,
+    _global_permit: OwnedSemaphorePermit,
+}
+
+impl Drop for JobSlot {
+    fn drop(&mut self) {
+        if let Ok(mut in_use) = self.in_use.lock() {
+            in_use[self.slot] = false;
+        }
+    }
+}
+
+impl RepoSlots {
+    fn new(limit: usize) -> RepoSlots {
+        let limit = limit.max(1);
+        RepoSlots {
+            permits: Arc::new(Semaphore::new(limit)),
+            in_use: Arc::new(Mutex::new(vec![false; limit])),
+        }
+    }
+}
+
+impl JobPool {
+    // `repo_limits` gives each repo path its limit; when a path is listed more than once (e.g.
+    // one Repo.toml entry per branch) the highest limit wins. Other repos get the default.
+    pub fn new(
+        max_concurrent_jobs: usize,
+        repo_limits: impl IntoIterator<Item = (String, usize)>,
+    ) -> JobPool {
+        let mut limits: HashMap<String, usize> = HashMap::new();
+        for (repo, limit) in repo_limits {
+            let max = limits.entry(repo).or_insert(limit);
+            *max = (*max).max(limit);
+        }
+        let repos = limits
+            .into_iter()
+            .map(|(repo, limit)| (repo, RepoSlots::new(limit)))
+            .collect();
+        JobPool {
+            global: Arc::new(Semaphore::new(max_concurrent_jobs.max(1))),
+            repos: Arc::new(Mutex::new(repos)),
+        }
+    }
+
+    // Wait for a free place for `repo`
+    pub async fn acquire(&self, repo: &str) -> JobSlot {
+        let slots = {
+            let mut repos = self.repos.lock().unwrap();
+            repos
+                .entry(repo.to_string())
+                .or_insert_with(|| RepoSlots::new(DEFAULT_REPO_CONCURRENT_JOBS))
+                .clone()
+        };
+        // Take the repo's permit first so a queued repo does not hold a global one
+        let repo_permit = slots.permits.acquire_owned().await.expect("pool closed");
+        let global_permit = self
+            .global
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("pool closed");
+        let slot = {
+            let mut in_use = slots.in_use.lock().unwrap();
+            // A permit guarantees a free slot
+            let slot = in_use.iter().position(|used| !used).unwrap_or(0);
+            in_use[slot] = true;
+            slot
+        };
+        JobSlot {
+            slot,
+            in_use: slots.in_use,
+            _repo_permit: repo_permit,
+            _global_permit: global_permit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_limits_and_slots() {
+        let pool = JobPool::new(2, [("a".to_string(), 2)]);
+        let a0 = pool.acquire("a").await;
+        let a1 = pool.acquire("a").await;
+        assert_eq!((a0.slot, a1.slot), (0, 1));
+
+        // The global limit holds back a third job, even for another repo
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.acquire("b").await.slot }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        drop(a0);
+        assert_eq!(waiting.await.unwrap(), 0);
+        // Slot 0 of repo a is free again
+        drop(a1);
+        assert_eq!(pool.acquire("a").await.slot, 0);
+    }
+
+    #[tokio::test]
+    async fn test_repo_limit() {
+        let pool = JobPool::new(4, []);
+        let first = pool.acquire("a").await;
+        let second = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.acquire("a").await.slot }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!second.is_finished());
+        drop(first);
+        assert_eq!(second.await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_repo_listed_twice_takes_highest_limit() {
+        let pool = JobPool::new(4, [("a".to_string(), 1), ("a".to_string(), 3)]);
+        let slots = [
+            pool.acquire("a").await,
+            pool.acquire("a").await,
+            pool.acquire("a").await,
+        ];
+        assert_eq!(slots.map(|s| s.slot), [0, 1, 2]);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::database::joblog::JobLog;
+use crate::database::run::Run;
+use crate::database::{database_size, vacuum, DbConn};
+use crate::logging::runlog::RunLogs;
+use crate::settings::Retention;
+use crate::util::format_size;
+use chrono::Local;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+// How often the daemon applies the retention settings
+pub const PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+// What a prune removed, or would remove with --dry-run
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    pub dry_run: bool,
+    pub runs: usize,
+    pub log_rows: usize,
+    // Step log directories, including those of runs no longer in the database
+    pub log_dirs: usize,
+    pub log_bytes: u64,
+    // Database size before and after VACUUM
+    pub vacuumed: Option<(u64, u64)>,
+}
+
+impl PruneReport {
+    pub fn is_empty(&self) -> bool {
+        self.runs == 0 && self.log_rows == 0 && self.log_dirs == 0
+    }
+}
+
+impl fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} run(s), {} log row(s) and {} step log dir(s) ({})",
+            if self.dry_run { "Would delete" } else { "Deleted" },
+            self.runs,
+            self.log_rows,
+            self.log_dirs,
+            format_size(self.log_bytes)
+        )?;
+        if let Some((before, after)) = self.vacuumed {
+            write!(
+                f,
+                "; database {} -> {}",
+                format_size(before),
+                format_size(after)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Delete runs, job log rows and step log files outside the retention limits, oldest first.
+// `log_dirs` are the step log directories on disk (see RunLogs::all). Runs still in progress
+// are never touched.
+pub fn prune(
+    db: &DbConn,
+    retention: &Retention,
+    log_dirs: Vec<(i64, RunLogs)>,
+    dry_run: bool,
+) -> Result<PruneReport, String> {
+    let cutoff = match retention.max_age {
+        Some(age) => {
+            let cutoff = chrono::Duration::from_std(age)
+                .ok()
+                .and_then(|age| Local::now().checked_sub_signed(age))
+                .ok_or_else(|| format!("max_age {:?} is too large", age))?;
+            Some(cutoff.to_rfc3339())
+        }
+        None => None,
+    };
+
+    let mut doomed: BTreeSet<i64> = BTreeSet::new();
+    if let Some(cutoff) = &cutoff {
+        doomed.extend(Run::finished_before(db, cutoff));
+    }
+    if let Some(keep) = retention.max_runs {
+        doomed.extend(Run::beyond_newest(db, keep));
+    }
+
+    // Directories left behind by runs that are no longer recorded go regardless
+    let dirs: HashMap<i64, RunLogs> = log_dirs.into_iter().collect();
+    let mut orphans: Vec<&RunLogs> = dirs
+        .iter()
+        .filter(|(id, _)| Run::get_run(db, **id).is_none())
+        .map(|(_, logs)| logs)
+        .collect();
+    orphans.sort_by(|a, b| a.dir().cmp(b.dir()));
+
+    if let Some(max_size) = retention.max_size {
+        let dir_size = |id: &i64| dirs.get(id).map(|l| l.size()).unwrap_or(0);
+        let mut total = database_size(db) + dirs.values().map(|l| l.size()).sum::<u64>();
+        total -= orphans.iter().map(|l| l.size()).sum::<u64>();
+        let runs = Run::finished_log_bytes(db);
+        for (id, log_bytes) in runs.iter().filter(|(id, _)| doomed.contains(id)) {
+            total = total.saturating_sub(dir_size(id) + log_bytes);
+        }
+        let kept: Vec<(i64, u64)> = runs
+            .into_iter()
+            .filter(|(id, _)| !doomed.contains(id))
+            .collect();
+        for (id, log_bytes) in kept.iter() {
+            if total <= max_size {
+                break;
+            }
+            doomed.insert(*id);
+            total = total.saturating_sub(dir_size(id) + log_bytes);
+        }
+    }
+
+    let doomed: Vec<i64> = doomed.into_iter().collect();
+    let doomed_dirs: Vec<&RunLogs> = doomed
+        .iter()
+        .filter_map(|id| dirs.get(id))
+        .chain(orphans)
+        .collect();
+    let mut report = PruneReport {
+        dry_run,
+        runs: doomed.len(),
+        log_rows: JobLog::count_prunable(db, &doomed, cutoff.as_deref()),
+        log_dirs: doomed_dirs.len(),
+        log_bytes: doomed_dirs.iter().map(|l| l.size()).sum(),
+        vacuumed: None,
+    };
+    if dry_run || report.is_empty() {
+        return Ok(report);
+    }
+
+    report.log_rows = JobLog::delete_prunable(db, &doomed, cutoff.as_deref())
+        .map_err(|e| format!("unable to delete job logs: {}", e))?;
+    Run::delete_runs(db, &doomed).map_err(|e| format!("unable to delete runs: {}", e))?;
+    for logs in doomed_dirs {
+        if let Err(e) = logs.remove() {
+            eprintln!("unable to remove {}: {}", logs.dir().display(), e);
+        }
+    }
+    let before = database_size(db);
+    vacuum(db).map_err(|e| format!("unable to vacuum database: {}", e))?;
+    report.vacuumed = Some((before, database_size(db)));
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SqliteConnection;
+    use std::fs;
+    use std::time::Duration;
+
+    fn add_run(db: &DbConn, branch: &str, started: &str, status: &str) -> i64 {
+        let id = Run::start(db, "git@example.com:org/repo", branch, "abc", "test", "").unwrap();
+        if status != "running" {
+            Run::finish(db, id, status, "", "");
+        }
+        db.lock()
+            .unwrap()
+            .execute(
+                "UPDATE runs SET start_time = ?1 WHERE id = ?2",
+                rusqlite::params![started, id],
+            )
+            .unwrap();
+        JobLog::add_job_logs(
+            db,
+            &[JobLog {
+                id: 0,
+                repo: "git@example.com:org/repo".to_string(),
+                log_message: format!("run {}", id),
+                logged_at: started.to_string(),
+                branch: branch.to_string(),
+                run_id: Some(id),
+                step_index: None,
+                level: "info".to_string(),
+            }],
+        );
+        id
+    }
+
+    #[test]
+    fn test_prune_by_age_and_count() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let old = add_run(&db, "main", "2020-01-01T00:00:00+00:00", "success");
+        let running = add_run(&db, "main", "2020-01-02T00:00:00+00:00", "running");
+        let now = Local::now().to_rfc3339();
+        let main = add_run(&db, "main", &now, "failed");
+        let dev = [
+            add_run(&db, "dev", &now, "success"),
+            add_run(&db, "dev", &now, "success"),
+            add_run(&db, "dev", &now, "success"),
+        ];
+
+        let retention = Retention {
+            max_age: Some(Duration::from_secs(30 * 24 * 3600)),
+            max_runs: Some(2),
+            max_size: None,
+        };
+        let root = std::env::temp_dir().join("phantom_ci-test-prune");
+        let dirs = |ids: &[i64]| -> Vec<(i64, RunLogs)> {
+            ids.iter()
+                .map(|id| (*id, RunLogs::in_dir(root.join(format!("run-{}", id)))))
+                .collect()
+        };
+        // Run 99 is not in the database
+        for (_, logs) in dirs(&[old, main, 99]) {
+            logs.create().unwrap();
+            fs::write(logs.dir().join("step-0.txt"), "output\n").unwrap();
+        }
+
+        let report = prune(&db, &retention, dirs(&[old, main, 99]), true).unwrap();
+        assert_eq!((report.runs, report.log_rows, report.log_dirs), (2, 2, 2));
+        assert!(Run::get_run(&db, old).is_some());
+
+        prune(&db, &retention, dirs(&[old, main, 99]), false).unwrap();
+        assert!(!root.join(format!("run-{}", old)).exists());
+        assert!(!root.join("run-99").exists());
+        assert!(root.join(format!("run-{}", main)).exists());
+        let _ = fs::remove_dir_all(&root);
+        let left: Vec<i64> = Run::get_runs(&db, None, 0).iter().map(|r| r.id).collect();
+        assert_eq!(left, vec![dev[2], dev[1], main, running]);
+        let rows = JobLog::get_logs_filtered(&db, &Default::default());
+        assert_eq!(rows.len(), 4);
+
+        let forever = Retention {
+            max_age: Some(Duration::from_secs(100_000_000 * 24 * 3600)),
+            ..retention
+        };
+        assert!(prune(&db, &forever, vec![], true).is_err());
+    }
+
+    #[test]
+    fn test_prune_by_size() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let now = Local::now().to_rfc3339();
+        add_run(&db, "main", &now, "success");
+        add_run(&db, "main", &now, "failed");
+        let running = add_run(&db, "main", &now, "running");
+
+        // Nothing fits in one byte, but a run in progress is kept
+        let retention = Retention {
+            max_size: Some(1),
+            ..Default::default()
+        };
+        let report = prune(&db, &retention, vec![], false).unwrap();
+        assert_eq!(report.runs, 2);
+        assert!(report.vacuumed.is_some());
+        let left: Vec<i64> = Run::get_runs(&db, None, 0).iter().map(|r| r.id).collect();
+        assert_eq!(left, vec![running]);
+    }
+}
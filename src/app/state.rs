@@ -1,24 +1,50 @@
+use crate::app::exec::{exec_workflow, ExecOptions};
+use crate::app::follow::follow_logs;
+use crate::app::pool::{JobPool, DEFAULT_MAX_CONCURRENT_JOBS, DEFAULT_REPO_CONCURRENT_JOBS};
+use crate::app::prune::{prune, PRUNE_INTERVAL};
 use crate::database::job::Job;
-use crate::database::SqliteConnection;
+use crate::database::{DbConn, SqliteConnection};
 use crate::options::{Arguments, Command};
-use crate::repo::{create_default_config, load_repos_from_config, Repo};
+use crate::parser::{validate_workflow, workflow_graph};
+use crate::repo::{create_default_config, load_repos_from_config, QueuedChange, Repo};
+use crate::parser::CancelToken;
+use crate::settings::{create_default_settings, load_settings, settings_path};
+use crate::settings::{Settings, DEFAULT_SHUTDOWN_TIMEOUT};
+use crate::util::lock::{lock_path, InstanceLock};
 use crate::util::service::configure_systemd;
-use crate::util::{default_config_path, default_repo_work_path_delete};
+use crate::util::{default_config_path, default_repo_work_path_delete, parse_time};
 use crate::util::{default_repo_work_path, default_repo_work_path_remove_cache_data};
 use chrono::Local;
 use clap::Parser;
+use log::{debug, error, info, warn};
 use rusqlite::Connection;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::fs::OpenOptions;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
 use std::path::Path;
 use std::process::exit;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::sync::mpsc::error::TryRecvError;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinSet;
 use tokio::time::interval;
-use crate::database::joblog::JobLog;
+use crate::database::joblog::{JobLog, LogFilter};
+use crate::database::queue::QueueEntry;
+use crate::database::run::{Run, RunStep};
+use crate::logging::runlog::RunLogs;
+
+// Time interrupted steps get to stop (SIGTERM, then SIGKILL) and their runs to be recorded
+const INTERRUPT_GRACE: Duration = Duration::from_secs(30);
+
+// A run the daemon has in progress
+#[derive(Debug, Clone)]
+struct ActiveJob {
+    repo: String,
+    branch: String,
+    cancel: CancelToken,
+}
 
 // Struct to hold application state
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -30,7 +56,7 @@ pub struct SerializedState {
 pub struct AppState {
     pub repos: Arc<Mutex<HashMap<String, Repo>>>,
     pub scm_internal: u64,
-    pub db_conn: Option<Arc<Mutex<Connection>>>,
+    pub db_conn: DbConn,
 }
 
 impl AppState {
@@ -45,30 +71,41 @@ impl Default for AppState {
 
 impl AppState {
     pub fn new() -> Self {
+        let arguments = Arguments::parse();
+        // `exec` runs against a local checkout and must not open or migrate the daemon's database
+        if let Some(Command::Exec { workflow, dir, record, notify }) = arguments.command {
+            run_exec(ExecOptions { workflow, dir, record, notify });
+        }
         if let Some(config_dir) = default_config_path() {
-            if let Ok(c) = SqliteConnection::new() {
-                let mut state = AppState {
-                    repos: Arc::new(Mutex::new(HashMap::new())),
-                    scm_internal: 60,
-                    db_conn: Some(Arc::new(Mutex::new(c.conn))),
-                };
-                state.process_arguments(config_dir.as_str());
-                state
-            } else {
-                panic!("Failed to connect to SQLite database");
+            match SqliteConnection::new() {
+                Ok(c) => {
+                    let mut state = AppState {
+                        repos: Arc::new(Mutex::new(HashMap::new())),
+                        scm_internal: 60,
+                        db_conn: c.into_shared(),
+                    };
+                    state.process_arguments(config_dir.as_str(), arguments);
+                    state
+                }
+                Err(e) => {
+                    eprintln!("Failed to open SQLite database: {}", e);
+                    exit(1);
+                }
             }
         } else {
             panic!("unable to find config path");
         }
     }
 
-    pub fn process_arguments(&mut self, config_dir: &str) {
+    pub fn process_arguments(&mut self, config_dir: &str, arguments: Arguments) {
         let repo_config = format!("{}Repo.toml", &config_dir);
         if !Path::new(&repo_config.as_str()).exists() {
             create_default_config(&repo_config);
         }
-        let arguments = Arguments::parse();
-
+        let settings_config = settings_path(config_dir);
+        if !Path::new(&settings_config).exists() {
+            create_default_settings(&settings_config);
+        }
         let mut run = false;
         match arguments.command {
             None => {
@@ -119,82 +156,336 @@ impl AppState {
             }
             Some(Command::Configure { sub }) => match sub.as_str() {
                 "service" => {
-                    configure_systemd();
+                    let shutdown_timeout = load_settings(config_dir)
+                        .and_then(|settings| settings.shutdown_timeout())
+                        .unwrap_or_else(|e| {
+                            eprintln!("{}", e);
+                            DEFAULT_SHUTDOWN_TIMEOUT
+                        });
+                    // The daemon waits shutdown_timeout for jobs, then interrupts the rest
+                    configure_systemd(shutdown_timeout + INTERRUPT_GRACE);
                 }
                 &_ => {
                     println!("Invalid subcommand");
                 }
             },
+            Some(Command::Prune { dry_run }) => {
+                let retention = match load_settings(config_dir)
+                    .and_then(|settings| settings.retention.resolve())
+                {
+                    Ok(retention) => retention,
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        exit(1);
+                    }
+                };
+                if retention.is_empty() {
+                    println!("No [retention] limits set in {}", settings_path(config_dir));
+                    exit(0);
+                }
+                match prune(&self.db_conn, &retention, RunLogs::all(), dry_run) {
+                    Ok(report) => println!("{}", report),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        exit(1);
+                    }
+                }
+            }
             Some(Command::Reset) => {
                 default_repo_work_path_remove_cache_data();
             }
-            Some(Command::Repo { sub }) => match sub.as_deref() {
-                Some(sub) => {
-                    let jobs = Job::get_jobs();
-                    let repo_config_path = format!("{}Repo.toml", config_dir);
-                    println!("Listing repos: {}", repo_config_path);
-                    let repo = load_repos_from_config(config_dir);
-                    for re in repo.iter() {
-                        if re.path.contains(sub) {
-                            let jobs = jobs.iter()
-                                .filter(|a| a.target_branch.eq_ignore_ascii_case(re.target_branch.as_str()))
-                                .filter(|a| a.repo.eq_ignore_ascii_case(re.path.as_str()))
-                                .cloned()
-                                .collect::<Vec<Job>>();
-                            let status = jobs.last().map(|j| j.status.as_str()).unwrap_or("no jobs");
-                            println!("{} - {} :: {}", re.path, re.target_branch, status);
-                        }
+            Some(Command::Repo { sub }) => {
+                let repo_config_path = format!("{}Repo.toml", config_dir);
+                println!("Listing repos: {}", repo_config_path);
+                let repo = load_repos_from_config(config_dir);
+                for re in repo.iter() {
+                    if sub.as_deref().is_some_and(|sub| !re.path.contains(sub)) {
+                        continue;
                     }
+                    let jobs = Job::get_jobs_by_repo(&self.db_conn, &re.path, &re.target_branch);
+                    let status = jobs.last().map(|j| j.status.as_str()).unwrap_or("no jobs");
+                    println!("{} - {} :: {}", re.path, re.target_branch, status);
                 }
-                None => {
-                    let jobs = Job::get_jobs();
-                    let repo_config_path = format!("{}Repo.toml", config_dir);
-                    println!("Listing repos: {}", repo_config_path);
-                    let repo = load_repos_from_config(config_dir);
-                    for re in repo.iter() {
-                        let jobs = jobs.iter()
-                            .filter(|a| a.target_branch.eq_ignore_ascii_case(re.target_branch.as_str()))
-                            .filter(|a| a.repo.eq_ignore_ascii_case(re.path.as_str()))
-                            .cloned()
-                            .collect::<Vec<Job>>();
-                        let status = jobs.last().map(|j| j.status.as_str()).unwrap_or("no jobs");
-                        println!("{} - {} :: {}", re.path, re.target_branch, status);
+            }
+            Some(Command::Search { query, repo, since, limit }) => {
+                if query.trim().is_empty() {
+                    eprintln!("Search query is empty");
+                    exit(1);
+                }
+                let since = since.map(|s| match parse_time(&s) {
+                    Ok(t) => t.to_rfc3339(),
+                    Err(e) => {
+                        eprintln!("Invalid --since: {}", e);
+                        exit(1);
                     }
+                });
+                let highlight = if std::io::stdout().is_terminal() {
+                    ("\x1b[1;31m", "\x1b[0m")
+                } else {
+                    ("**", "**")
+                };
+                let hits = match JobLog::search(
+                    &self.db_conn,
+                    &query,
+                    repo.as_deref(),
+                    since.as_deref(),
+                    limit,
+                    highlight,
+                ) {
+                    Ok(hits) => hits,
+                    Err(e) => {
+                        eprintln!("Search failed: {}", e);
+                        exit(1);
+                    }
+                };
+                if hits.is_empty() {
+                    println!("No matches for {}", query);
+                }
+                // Oldest first, so the first line shows when the message first appeared
+                for hit in hits.iter() {
+                    let log = &hit.log;
+                    let run = log
+                        .run_id
+                        .map(|id| format!("#{}", id))
+                        .unwrap_or_else(|| "-".to_string());
+                    let step = log
+                        .step_index
+                        .map(|idx| format!(" [step {}]", idx))
+                        .unwrap_or_default();
+                    let branch = if log.branch.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" [{}]", log.branch)
+                    };
+                    println!("{}{} {} {}{}", run, step, log.logged_at, log.repo, branch);
+                    println!("    {}", hit.snippet.replace('\n', " "));
                 }
             }
-            Some(Command::Jobs {sub}) => match sub.as_deref() {
-                Some(sub) => {
-                    let jobs = Job::get_jobs();
-                    for job in jobs.iter() {
-                        if job.repo.contains(sub) {
-                            println!("{:?}\n", job);
-                        }
+            Some(Command::Jobs { sub, limit }) => {
+                let runs = Run::get_runs(&self.db_conn, sub.as_deref(), limit);
+                if runs.is_empty() {
+                    println!("No runs recorded");
+                }
+                for run in runs.iter() {
+                    let sha = run.sha.get(..8).unwrap_or(run.sha.as_str());
+                    let matrix = if run.matrix.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" {{{}}}", run.matrix)
+                    };
+                    println!(
+                        "#{} {} [{}] {}{} ({}) :: {} :: {} -> {}",
+                        run.id,
+                        run.repo,
+                        run.branch,
+                        sha,
+                        matrix,
+                        run.trigger,
+                        run.status,
+                        run.start_time,
+                        run.finish_time
+                    );
+                }
+            }
+            Some(Command::Queue { sub }) => {
+                let entries: Vec<QueueEntry> = QueueEntry::get_all(&self.db_conn)
+                    .into_iter()
+                    .filter(|e| sub.as_deref().is_none_or(|sub| e.repo.contains(sub)))
+                    .collect();
+                if entries.is_empty() {
+                    println!("Queue is empty");
+                }
+                for entry in entries.iter() {
+                    let sha = entry.sha.get(..8).unwrap_or(entry.sha.as_str());
+                    let claimed = if !entry.claimed_at.is_empty() {
+                        format!(" -> {}", entry.claimed_at)
+                    } else if !entry.ready_at.is_empty() {
+                        format!(" (quiet period until {})", entry.ready_at)
+                    } else {
+                        String::new()
+                    };
+                    println!(
+                        "{} [{}] {} :: {} :: {}{}",
+                        entry.repo, entry.branch, sha, entry.status, entry.enqueued_at, claimed
+                    );
+                }
+            }
+            Some(Command::Show { run_id }) => match Run::get_run(&self.db_conn, run_id) {
+                Some(run) => {
+                    println!("Run #{}", run.id);
+                    println!("  repo:     {}", run.repo);
+                    println!("  branch:   {}", run.branch);
+                    println!("  sha:      {}", run.sha);
+                    println!("  trigger:  {}", run.trigger);
+                    if !run.matrix.is_empty() {
+                        println!("  matrix:   {}", run.matrix);
+                    }
+                    println!("  status:   {}", run.status);
+                    println!("  started:  {}", run.start_time);
+                    println!("  finished: {}", run.finish_time);
+                    if !run.error_message.is_empty() {
+                        println!("  error:    {}", run.error_message);
+                    }
+                    if !run.result.is_empty() {
+                        println!("  result:   {}", run.result);
+                    }
+                    println!("  steps:");
+                    for step in RunStep::get_steps(&self.db_conn, run.id) {
+                        let code = step
+                            .exit_code
+                            .map(|c| c.to_string())
+                            .unwrap_or_else(|| "-".to_string());
+                        let command = if step.name.is_empty() {
+                            step.command.clone()
+                        } else {
+                            format!("{}: {}", step.name, step.command)
+                        };
+                        let attempt = if step.attempt > 1 {
+                            format!(" attempt {}", step.attempt)
+                        } else {
+                            String::new()
+                        };
+                        println!(
+                            "    [step {}]{} {} (exit {}) in {:.2?} :: {}",
+                            step.step_index,
+                            attempt,
+                            step.status,
+                            code,
+                            Duration::from_millis(step.duration_ms as u64),
+                            command
+                        );
                     }
                 }
                 None => {
-                    let jobs = Job::get_jobs();
-                    for job in jobs.iter() {
-                        println!("{:?}\n", job);
+                    println!("Run #{} not found", run_id);
+                }
+            },
+            Some(Command::Exec { .. }) => unreachable!("exec is handled before the database opens"),
+            Some(Command::Validate { workflows }) => {
+                let mut valid = true;
+                for workflow in workflows.iter() {
+                    match validate_workflow(workflow) {
+                        Ok(summary) => println!("✅ {} :: {}", workflow, summary),
+                        Err(problems) => {
+                            valid = false;
+                            println!("❌ {}", workflow);
+                            for problem in problems {
+                                println!("  - {}", problem.replace('\n', "\n    "));
+                            }
+                        }
                     }
                 }
+                if !valid {
+                    exit(1);
+                }
             }
-            Some(Command::Logs { sub, repo, branch, limit }) => {
-                // Determine filter precedence: --repo overrides positional sub
-                let repo_filter = repo.or(sub);
-                let mut logs = if let Some(r) = &repo_filter {
-                    JobLog::get_logs_by_repo(r, limit)
-                } else {
-                    JobLog::get_logs_limited(limit)
+            Some(Command::Graph { workflow }) => match workflow_graph(&workflow) {
+                Ok(graph) => print!("{}", graph),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    exit(1);
+                }
+            },
+            // Full step logs, unless --run narrows a listing of log rows
+            Some(Command::Logs {
+                run: Some(run_id),
+                step,
+                raw,
+                ref branch,
+                ref status,
+                ref since,
+                ref until,
+                ref level,
+                ..
+            }) if step.is_some()
+                || raw
+                || [branch, status, since, until, level].iter().all(|f| f.is_none()) =>
+            {
+                if Run::get_run(&self.db_conn, run_id).is_none() {
+                    eprintln!("Run #{} not found", run_id);
+                    exit(1);
+                }
+                let Some(logs) = RunLogs::for_run(run_id) else {
+                    eprintln!("Unable to find the cache directory for step logs");
+                    exit(1);
                 };
-
-                // Optional best-effort branch filter (branch appears inside the log_message)
-                if let Some(br) = &branch {
-                    let br_lc = br.to_lowercase();
-                    logs.retain(|l| l
-                        .log_message
-                        .to_lowercase()
-                        .contains(&format!(":{}", br_lc)));
+                let steps = match step {
+                    Some(idx) => vec![idx],
+                    None => logs.steps(),
+                };
+                if steps.is_empty() {
+                    println!("No step logs recorded for run #{}", run_id);
+                }
+                let titles: HashMap<usize, String> = RunStep::get_steps(&self.db_conn, run_id)
+                    .into_iter()
+                    .map(|s| {
+                        let title = if s.name.is_empty() { s.command } else { s.name };
+                        (s.step_index as usize, title)
+                    })
+                    .collect();
+                for idx in steps {
+                    match logs.read(idx, raw) {
+                        Ok(text) => {
+                            let title = titles.get(&idx).map(String::as_str).unwrap_or("");
+                            println!("==> [step {}] {} <==", idx, title);
+                            print!("{}", text);
+                        }
+                        Err(e) => {
+                            eprintln!("No log for step {} of run #{}: {}", idx, run_id, e);
+                            exit(1);
+                        }
+                    }
                 }
+            }
+            Some(Command::Logs { follow: true, sub, repo, .. }) => {
+                // Same matching as the row listing: --repo exact, positional substring
+                let repo_contains = if repo.is_none() { sub } else { None };
+                let success = tokio::task::block_in_place(|| {
+                    Handle::current().block_on(follow_logs(
+                        &self.db_conn,
+                        repo.as_deref(),
+                        repo_contains.as_deref(),
+                    ))
+                });
+                if !success {
+                    exit(1);
+                }
+            }
+            Some(Command::Logs {
+                sub,
+                repo,
+                branch,
+                status,
+                since,
+                until,
+                level,
+                limit,
+                run,
+                ..
+            }) => {
+                let time = |arg: &str, value: Option<String>| -> Option<String> {
+                    value.map(|v| match parse_time(&v) {
+                        Ok(t) => t.to_rfc3339(),
+                        Err(e) => {
+                            eprintln!("Invalid --{}: {}", arg, e);
+                            exit(1);
+                        }
+                    })
+                };
+                // --repo is an exact match and overrides the positional substring
+                let filter = LogFilter {
+                    repo_contains: if repo.is_none() { sub } else { None },
+                    repo,
+                    branch,
+                    run_id: run,
+                    level,
+                    status,
+                    since: time("since", since),
+                    until: time("until", until),
+                    // A single run is listed in full
+                    limit: if run.is_some() { 0 } else { limit },
+                };
+                let logs = JobLog::get_logs_filtered(&self.db_conn, &filter);
 
                 // Pretty print
                 for log in logs.iter() {
@@ -206,12 +497,23 @@ impl AppState {
                         .next()
                         .unwrap_or("")
                         .trim();
-                    let truncated = if first_line.len() > 160 {
-                        format!("{}…", &first_line[..160])
+                    // Step titles may contain multi-byte characters, so cut on a char boundary
+                    let truncated = if first_line.chars().count() > 160 {
+                        format!("{}…", first_line.chars().take(160).collect::<String>())
                     } else {
                         first_line.to_string()
                     };
-                    println!("[{}] {} :: {}", ts, log.repo, truncated);
+                    let mut source = log.repo.clone();
+                    if !log.branch.is_empty() {
+                        source.push_str(&format!(" [{}]", log.branch));
+                    }
+                    if let Some(run_id) = log.run_id {
+                        source.push_str(&format!(" #{}", run_id));
+                    }
+                    if log.level != "info" {
+                        source.push_str(&format!(" {}", log.level));
+                    }
+                    println!("[{}] {} :: {}", ts, source, truncated);
                 }
             }
         }
@@ -265,10 +567,7 @@ impl AppState {
                     left_out.remove(&repo.name);
                     repo.prepare();
                     self.add_repo_to_state(repo.clone().name, repo.to_owned());
-                    if !Job::check_exists(
-                        String::from(&repo.path),
-                        String::from(&repo.target_branch),
-                    ) {
+                    if !Job::check_exists(&self.db_conn, &repo.path, &repo.target_branch) {
                         let mut job = Job {
                             id: 0,
                             repo: repo.path.clone(),
@@ -283,7 +582,7 @@ impl AppState {
                             sha: repo.last_sha.clone().unwrap_or("".to_string()),
                             target_branch: repo.target_branch.clone(),
                         };
-                        job.add_job()
+                        job.add_job(&self.db_conn)
                     }
                 });
             left_out.iter().for_each(|remove_repo| {
@@ -301,14 +600,83 @@ impl AppState {
     }
 
     pub fn set_db_conn(&mut self, db_conn: Connection) {
-        self.db_conn = Some(Arc::new(Mutex::new(db_conn)));
+        self.db_conn = Arc::new(Mutex::new(db_conn));
     }
 
-    pub async fn poll_repos(&mut self) {
-        println!(
-            "Starting Git SCM polling...\n     config: {}",
-            default_config_path().unwrap()
+    // Apply the [retention] settings; re-read every time so edits take effect without a restart
+    async fn apply_retention(&self) {
+        let Some(config_dir) = default_config_path() else {
+            return;
+        };
+        let retention = match load_settings(&config_dir).and_then(|s| s.retention.resolve()) {
+            Ok(retention) if !retention.is_empty() => retention,
+            Ok(_) => return,
+            Err(e) => {
+                error!("Retention not applied: {}", e);
+                return;
+            }
+        };
+        let db = self.db_conn.clone();
+        let result =
+            tokio::task::spawn_blocking(move || prune(&db, &retention, RunLogs::all(), false))
+                .await;
+        match result {
+            Ok(Ok(report)) if !report.is_empty() => {
+                info!("{}", report);
+                println!("Retention: {}", report);
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!("Retention failed: {}", e),
+            Err(e) => error!("Retention failed: {}", e),
+        }
+    }
+
+    // Clean up after a daemon that stopped mid-run: its runs are closed as 'interrupted' and
+    // the builds it had claimed are queued again, or dropped with requeue_interrupted = false
+    fn recover_interrupted(&self, requeue: bool) {
+        let runs = Run::interrupt_running(
+            &self.db_conn,
+            "daemon stopped while the run was in progress",
         );
+        Job::interrupt_running(&self.db_conn);
+        // Their live step logs were never compressed and stripped
+        for logs in runs.iter().filter_map(|id| RunLogs::for_run(*id)) {
+            if logs.dir().exists() {
+                if let Err(e) = logs.finish() {
+                    warn!("unable to finish step logs in {}: {}", logs.dir().display(), e);
+                }
+            }
+        }
+        let runs = runs.len();
+        let builds = if requeue {
+            QueueEntry::requeue_claimed(&self.db_conn)
+        } else {
+            QueueEntry::remove_claimed(&self.db_conn)
+        };
+        if runs > 0 || builds > 0 {
+            let msg = format!(
+                "Marked {} run(s) interrupted; {} {} unfinished build(s)",
+                runs,
+                if requeue { "re-queued" } else { "dropped" },
+                builds
+            );
+            info!("{}", msg);
+            println!("{}", msg);
+        }
+    }
+
+    pub async fn poll_repos(&mut self) {
+        let config_dir = default_config_path().unwrap();
+        // Held until the process exits; a second daemon on this config dir stops here
+        let _lock = match InstanceLock::acquire(&lock_path(&config_dir)) {
+            Ok(lock) => lock,
+            Err(e) => {
+                error!("{}", e);
+                eprintln!("{}", e);
+                exit(1);
+            }
+        };
+        println!("Starting Git SCM polling...\n     config: {}", config_dir);
 
         self.add_repos_from_config();
 
@@ -316,33 +684,246 @@ impl AppState {
         let mut ticker = interval(interval_duration);
         #[allow(unused)]
         let (mut tx, mut rx) = tokio::sync::mpsc::channel::<String>(100);
-
+        // Step output streams through the channel while a run is in progress, so it has to be
+        // drained continuously; everything on it is already printed and logged by the runner
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                debug!("{}", msg);
+            }
+        });
+
+        let settings = load_settings(&config_dir).unwrap_or_else(|e| {
+            error!("{}", e);
+            Settings::default()
+        });
+        let shutdown_timeout = settings.shutdown_timeout().unwrap_or_else(|e| {
+            error!("{}", e);
+            DEFAULT_SHUTDOWN_TIMEOUT
+        });
+        let repo_limits: Vec<(String, usize)> = self
+            .repos
+            .lock()
+            .unwrap()
+            .values()
+            .map(|repo| {
+                let limit = repo
+                    .max_concurrent_jobs
+                    .unwrap_or(DEFAULT_REPO_CONCURRENT_JOBS);
+                (repo.path.clone(), limit)
+            })
+            .collect();
+        let pool = JobPool::new(
+            settings
+                .max_concurrent_jobs
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_JOBS),
+            repo_limits,
+        );
+        self.recover_interrupted(settings.requeue_interrupted.unwrap_or(true));
+
+        let mut sigterm = signal(SignalKind::terminate()).expect("unable to listen for SIGTERM");
+        let mut sigint = signal(SignalKind::interrupt()).expect("unable to listen for SIGINT");
+        let mut workers: JoinSet<()> = JoinSet::new();
+        // Runs in progress, by queue entry id
+        let active: Arc<Mutex<HashMap<i64, ActiveJob>>> = Arc::new(Mutex::new(HashMap::new()));
+        // Cancelled on shutdown; jobs still waiting for the pool give up their place
+        let stopping = CancelToken::default();
+        // Queue entries that have a worker, waiting on the pool or running
+        let dispatched: Arc<Mutex<HashSet<i64>>> = Arc::new(Mutex::new(HashSet::new()));
+
+        let mut last_prune: Option<Instant> = None;
         loop {
             let tx_clone = tx.clone();
-            // let mut rx_clone = &mut rx;
 
-            ticker.tick().await;
-            let mut repos = self.repos.lock().unwrap().to_owned();
-            for (_, repo) in repos.iter_mut() {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = sigterm.recv() => break,
+                _ = sigint.recv() => break,
+            }
+            while workers.try_join_next().is_some() {}
+            if last_prune.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL) {
+                last_prune = Some(Instant::now());
+                self.apply_retention().await;
+            }
+            // Work on a copy so the lock is not held while polling remotes; changes are queued.
+            // git runs on the blocking pool so running jobs are not held up by a slow fetch.
+            let repos = self.repos.lock().unwrap().clone();
+            for (name, mut repo) in repos {
                 println!("     - {}         ({}) ✅", repo.path, repo.target_branch);
-                repo.check_repo_changes();
-                repo.check_repo_triggered(tx_clone.clone()).await
+                let db = self.db_conn.clone();
+                let polled = tokio::task::spawn_blocking(move || {
+                    let changes = repo.check_repo_changes(&db);
+                    (repo, changes)
+                })
+                .await;
+                match polled {
+                    Ok((repo, changes)) => {
+                        // Stop the outdated build now rather than once the new one can start,
+                        // which a quiet period or a full pool may put off for a while
+                        for change in changes.iter() {
+                            if repo.cancels_superseded(&change.branch) {
+                                supersede(&active, &repo.path, change);
+                            }
+                        }
+                        self.repos.lock().unwrap().insert(name, repo);
+                    }
+                    Err(e) => error!("polling {} failed: {}", name, e),
+                }
             }
-            self.repos.lock().unwrap().clone_from(&repos);
-
-            drop(tx_clone);
 
-            loop {
-                match rx.try_recv() {
-                    Ok(msg) => println!("{}", msg),
-                    Err(TryRecvError::Empty) => break, // nothing left to read
-                    Err(TryRecvError::Disconnected) => {
-                        // println!("Channel closed.");
-                        break;
-                    }
+            // Jobs run on the pool so the next tick is not held up by a long workflow; builds in
+            // their quiet period are left until it is over
+            for entry in QueueEntry::get_ready(&self.db_conn) {
+                if !dispatched.lock().unwrap().insert(entry.id) {
+                    continue;
                 }
+                // Prefer the entry tracking this branch when a repo is listed once per branch
+                let repo = {
+                    let repos = self.repos.lock().unwrap();
+                    let mut candidates: Vec<&Repo> =
+                        repos.values().filter(|r| r.path == entry.repo).collect();
+                    candidates.sort_by_key(|r| r.target_branch != entry.branch);
+                    candidates.first().map(|r| (*r).clone())
+                };
+                let Some(repo) = repo else {
+                    println!(
+                        "Dropping queued build of {} [branch: {}]: repo is no longer configured",
+                        entry.repo, entry.branch
+                    );
+                    QueueEntry::complete(&self.db_conn, entry.id);
+                    dispatched.lock().unwrap().remove(&entry.id);
+                    continue;
+                };
+                Job::mark_queued(&self.db_conn, &repo.path, &entry.branch);
+                let pool = pool.clone();
+                let db = self.db_conn.clone();
+                let tx = tx_clone.clone();
+                let active = active.clone();
+                let stopping = stopping.clone();
+                let dispatched = dispatched.clone();
+                let id = entry.id;
+                workers.spawn(async move {
+                    let work = async move {
+                        let slot = tokio::select! {
+                            slot = pool.acquire(&repo.path) => slot,
+                            _ = stopping.cancelled() => return,
+                        };
+                        // Registered before the claim so a newer push cannot miss this run
+                        let cancel = CancelToken::default();
+                        let job = ActiveJob {
+                            repo: repo.path.clone(),
+                            branch: entry.branch.clone(),
+                            cancel: cancel.clone(),
+                        };
+                        active.lock().unwrap().insert(entry.id, job);
+                        if stopping.is_cancelled() || !QueueEntry::claim(&db, entry.id) {
+                            active.lock().unwrap().remove(&entry.id);
+                            return;
+                        }
+                        let work_dir = tokio::task::spawn_blocking({
+                            let (repo, slot) = (repo.clone(), slot.slot);
+                            move || repo.job_work_dir(slot)
+                        })
+                        .await
+                        .unwrap_or_else(|e| Err(anyhow::anyhow!("worktree task failed: {}", e)));
+                        match work_dir {
+                            Ok(work_dir) => {
+                                let branch = &entry.branch;
+                                repo.run_triggered(&db, branch, &work_dir, tx, cancel.clone())
+                                    .await
+                            }
+                            Err(e) => {
+                                error!("{} ({}): {}", repo.path, entry.branch, e);
+                                Job::update_status(&db, &repo.path, &entry.branch, "failed");
+                            }
+                        }
+                        active.lock().unwrap().remove(&entry.id);
+                        // An interrupted build stays claimed so the next start can queue it again
+                        if cancel.reason().as_deref() != Some("interrupted") {
+                            QueueEntry::complete(&db, entry.id);
+                        }
+                        drop(slot);
+                    };
+                    work.await;
+                    // An entry whose quiet period restarted before it was claimed is still
+                    // pending and gets a new worker once it is ready
+                    dispatched.lock().unwrap().remove(&id);
+                });
             }
+
+            drop(tx_clone);
+        }
+
+        drain(&mut workers, &active, &stopping, shutdown_timeout).await;
+    }
+}
+
+// `phantom_ci exec`: run the workflow and exit with its result
+fn run_exec(options: ExecOptions) -> ! {
+    // Argument handling is synchronous but already inside the runtime
+    let result =
+        tokio::task::block_in_place(|| Handle::current().block_on(exec_workflow(options)));
+    match result {
+        Ok(true) => exit(0),
+        Ok(false) => exit(1),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    }
+}
+
+// Cancel the runs of the changed branch that started from an older queue entry
+fn supersede(active: &Mutex<HashMap<i64, ActiveJob>>, repo: &str, change: &QueuedChange) {
+    for (id, job) in active.lock().unwrap().iter() {
+        if *id < change.queue_id && job.repo == repo && job.branch == change.branch {
+            let msg = format!(
+                "Cancelling superseded build of {} [branch: {}]; {} is queued",
+                job.repo, job.branch, change.sha
+            );
+            info!("{}", msg);
+            println!("{}", msg);
+            job.cancel.cancel("superseded");
+        }
+    }
+}
+
+// Stop taking new work and give running jobs `timeout` to finish; whatever is still running
+// then is interrupted, which terminates its steps and records the run as 'interrupted'
+async fn drain(
+    workers: &mut JoinSet<()>,
+    active: &Mutex<HashMap<i64, ActiveJob>>,
+    stopping: &CancelToken,
+    timeout: Duration,
+) {
+    stopping.cancel("interrupted");
+    let running = active.lock().unwrap().len();
+    let msg = format!(
+        "Shutting down; waiting up to {:?} for {} running job(s)",
+        timeout, running
+    );
+    info!("{}", msg);
+    println!("{}", msg);
+    let finished = tokio::time::timeout(timeout, async {
+        while workers.join_next().await.is_some() {}
+    })
+    .await;
+    if finished.is_err() {
+        let cancels: Vec<CancelToken> = active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|job| job.cancel.clone())
+            .collect();
+        let msg = format!("Interrupting {} job(s) still running", cancels.len());
+        warn!("{}", msg);
+        println!("{}", msg);
+        for cancel in cancels {
+            cancel.cancel("interrupted");
         }
+        let _ = tokio::time::timeout(INTERRUPT_GRACE, async {
+            while workers.join_next().await.is_some() {}
+        })
+        .await;
     }
 }
 
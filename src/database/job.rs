@@ -1,7 +1,5 @@
-use crate::database::SqliteConnection;
-#[allow(unused)]
-use rusqlite::fallible_iterator::FallibleIterator;
-use rusqlite::params;
+use crate::database::DbConn;
+use rusqlite::{params, Row, ToSql};
 use std::path::Path;
 use chrono::Local;
 
@@ -40,11 +38,26 @@ pub struct Job {
 }
 
 impl Job {
-    pub fn add_job(&mut self) {
-        let connection = SqliteConnection::new();
-        let conn = connection.unwrap().conn;
+    fn from_row(row: &Row) -> rusqlite::Result<Job> {
+        Ok(Job {
+            id: row.get(0)?,
+            repo: row.get(1)?,
+            status: row.get(2)?,
+            priority: row.get(3)?,
+            created_at: row.get(4)?,
+            updated_at: row.get(5)?,
+            start_time: row.get(6)?,
+            finish_time: row.get(7)?,
+            error_message: row.get(8)?,
+            result: row.get(9)?,
+            sha: row.get(10)?,
+            target_branch: row.get(11)?,
+        })
+    }
 
-        if !Job::check_exists(self.repo.clone(), self.target_branch.clone()) {
+    pub fn add_job(&mut self, db: &DbConn) {
+        if !Job::check_exists(db, &self.repo, &self.target_branch) {
+            let conn = db.lock().unwrap();
             match conn.execute(
                 "INSERT INTO jobs (repo, status, priority, created_at, updated_at, start_time, finish_time, error_message, result, sha, target_branch) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
                 params![self.repo, self.status, self.priority, Local::now().to_rfc3339(), Local::now().to_rfc3339(), self.start_time, self.finish_time, self.error_message, self.result, self.sha, self.target_branch],
@@ -57,46 +70,51 @@ impl Job {
         }
     }
 
-    pub fn check_exists(repo: String, branch: String) -> bool {
-        Job::get_jobs()
-            .into_iter()
-            .any(|job| job.repo == repo && job.target_branch == branch)
+    pub fn check_exists(db: &DbConn, repo: &str, branch: &str) -> bool {
+        let conn = db.lock().unwrap();
+        conn.prepare_cached(
+            "SELECT EXISTS(SELECT 1 FROM jobs WHERE repo = ?1 AND target_branch = ?2)",
+        )
+        .and_then(|mut stmt| stmt.query_row(params![repo, branch], |row| row.get(0)))
+        .unwrap_or(false)
     }
 
-    pub fn update_sha(repo: String, target_branch: String, sha: String) {
-        let connection = SqliteConnection::new();
-        let conn = connection.unwrap().conn;
-
-        match conn.execute(
-            "UPDATE jobs SET sha = ?1 WHERE repo = ?2 AND target_branch = ?3",
-            params![sha, repo, target_branch],
-        ) {
-            Ok(rows_updated) => {
-                if rows_updated > 0 {
-                    println!("SHA Update successful");
-                    Self::update_updated_time(repo, target_branch);
-                } else {
-                    println!("No matching job to update");
-                }
-            }
-            Err(error) => {
-                println!("Update error: {}", error);
-            }
-        }
+    // Last known SHA for a repo/branch, empty if the branch has not been seen yet
+    pub fn get_sha(db: &DbConn, repo: &str, branch: &str) -> String {
+        let conn = db.lock().unwrap();
+        conn.prepare_cached(
+            "SELECT sha FROM jobs WHERE repo = ?1 AND target_branch = ?2 ORDER BY id DESC LIMIT 1",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_row(params![repo, branch], |row| {
+                row.get::<_, Option<String>>(0)
+            })
+        })
+        .ok()
+        .flatten()
+        .unwrap_or_default()
     }
 
-    pub fn update_status(repo: String, target_branch: String, status: String) {
-        let connection = SqliteConnection::new();
-        let conn = connection.unwrap().conn;
+    fn update_column(db: &DbConn, column: JobColumn, value: &str, repo: &str, target_branch: &str) {
+        let column = match column {
+            JobColumn::Status => "status",
+            JobColumn::Sha => "sha",
+            JobColumn::StartTime => "start_time",
+            JobColumn::FinishTime => "finish_time",
+            _ => return,
+        };
+        let conn = db.lock().unwrap();
+        let query = format!(
+            "UPDATE jobs SET {} = ?1, updated_at = ?2 WHERE repo = ?3 AND target_branch = ?4",
+            column
+        );
 
-        match conn.execute(
-            "UPDATE jobs SET status = ?1 WHERE repo = ?2 AND target_branch = ?3",
-            params![status, repo, target_branch],
-        ) {
+        match conn.prepare_cached(&query).and_then(|mut stmt| {
+            stmt.execute(params![value, Local::now().to_rfc3339(), repo, target_branch])
+        }) {
             Ok(rows_updated) => {
                 if rows_updated > 0 {
-                    println!("Status Update successful");
-                    Self::update_updated_time(repo, target_branch);
+                    println!("Job {} update successful", column);
                 } else {
                     println!("No matching job to update");
                 }
@@ -107,119 +125,79 @@ impl Job {
         }
     }
 
-    pub fn update_updated_time(repo: String, target_branch: String) {
-        let connection = SqliteConnection::new();
-        let conn = connection.unwrap().conn;
-
-        match conn.execute(
-            "UPDATE jobs SET updated_at = ?1 WHERE repo = ?2 AND target_branch = ?3",
-            params![Local::now().to_rfc3339(), repo, target_branch],
-        ) {
-            Ok(rows_updated) => {
-                if rows_updated > 0 {
-                    println!("Status Update successful");
-                } else {
-                    println!("No matching job to update");
-                }
-            }
-            Err(error) => {
-                println!("Update error: {}", error);
-            }
-        }
+    pub fn update_sha(db: &DbConn, repo: &str, target_branch: &str, sha: &str) {
+        Self::update_column(db, JobColumn::Sha, sha, repo, target_branch);
     }
 
-    pub fn update_start_time(repo: String, target_branch: String) {
-        let connection = SqliteConnection::new();
-        let conn = connection.unwrap().conn;
+    pub fn update_status(db: &DbConn, repo: &str, target_branch: &str, status: &str) {
+        Self::update_column(db, JobColumn::Status, status, repo, target_branch);
+    }
 
-        match conn.execute(
-            "UPDATE jobs SET start_time = ?1 WHERE repo = ?2 AND target_branch = ?3",
-            params![Local::now().to_rfc3339(), repo, target_branch],
-        ) {
-            Ok(rows_updated) => {
-                if rows_updated > 0 {
-                    println!("Status Update successful");
-                } else {
-                    println!("No matching job to update");
-                }
-            }
-            Err(error) => {
-                println!("Update error: {}", error);
-            }
-        }
+    pub fn update_start_time(db: &DbConn, repo: &str, target_branch: &str) {
+        Self::update_column(db, JobColumn::StartTime, &Local::now().to_rfc3339(), repo, target_branch);
     }
 
-    pub fn update_finished_time(repo: String, target_branch: String) {
-        let connection = SqliteConnection::new();
-        let conn = connection.unwrap().conn;
+    pub fn update_finished_time(db: &DbConn, repo: &str, target_branch: &str) {
+        Self::update_column(db, JobColumn::FinishTime, &Local::now().to_rfc3339(), repo, target_branch);
+    }
 
-        match conn.execute(
-            "UPDATE jobs SET finish_time = ?1 WHERE repo = ?2 AND target_branch = ?3",
+    // Show a build of the branch as waiting, unless its current run is still going: that run's
+    // own status takes over when it ends
+    pub fn mark_queued(db: &DbConn, repo: &str, target_branch: &str) {
+        let conn = db.lock().unwrap();
+        if let Err(error) = conn.execute(
+            "UPDATE jobs SET status = 'queued', updated_at = ?1 WHERE repo = ?2 AND target_branch = ?3 AND status != 'running'",
             params![Local::now().to_rfc3339(), repo, target_branch],
         ) {
-            Ok(rows_updated) => {
-                if rows_updated > 0 {
-                    println!("Status Update successful");
-                } else {
-                    println!("No matching job to update");
-                }
-            }
-            Err(error) => {
-                println!("Update error: {}", error);
-            }
+            println!("Update error: {}", error);
         }
     }
 
+    // Jobs a previous daemon left running. Queued jobs keep their status: their builds are
+    // still in the queue table and are dispatched again.
+    pub fn interrupt_running(db: &DbConn) -> usize {
+        let conn = db.lock().unwrap();
+        conn.execute(
+            "UPDATE jobs SET status = 'interrupted', updated_at = ?1 WHERE status = 'running'",
+            params![Local::now().to_rfc3339()],
+        )
+        .unwrap_or_else(|error| {
+            println!("Update error: {}", error);
+            0
+        })
+    }
+
     // pub fn read_by_date_range() -> Vec<Job> {}
     // pub fn read_by_id_range() -> Vec<Job> {}
-    pub fn get_jobs() -> Vec<Job> {
-        if let Ok(sql) = SqliteConnection::new() {
-            let mut jobs: Vec<Job> = vec![];
-            // let res = sql.conn.prepare("SELECT * FROM jobs");
+    #[allow(dead_code)]
+    pub fn get_jobs(db: &DbConn) -> Vec<Job> {
+        Self::query_jobs(db, "SELECT * FROM jobs", params![])
+    }
 
-            if let Ok(mut stmt) = sql.conn.prepare("SELECT * FROM jobs") {
-                let job_iter = stmt.query_map([], |row| {
-                    Ok(Job {
-                        id: row.get(0)?,
-                        repo: row.get(1)?,
-                        status: row.get(2)?,
-                        priority: row.get(3)?,
-                        created_at: row.get(4)?,
-                        updated_at: row.get(5)?,
-                        start_time: row.get(6)?,
-                        finish_time: row.get(7)?,
-                        error_message: row.get(8)?,
-                        result: row.get(9)?,
-                        sha: row.get(10)?,
-                        target_branch: row.get(11)?,
-                    })
-                });
+    #[allow(dead_code)]
+    pub fn get_jobs_by_status(db: &DbConn, status: &str) -> Vec<Job> {
+        Self::query_jobs(db, "SELECT * FROM jobs WHERE status = ?1", params![status])
+    }
 
-                if let Ok(job_iter) = job_iter {
-                    jobs.extend(job_iter.flatten());
-                }
-            }
+    pub fn get_jobs_by_repo(db: &DbConn, repo: &str, branch: &str) -> Vec<Job> {
+        Self::query_jobs(
+            db,
+            "SELECT * FROM jobs WHERE repo = ?1 AND target_branch = ?2 ORDER BY id",
+            params![repo, branch],
+        )
+    }
 
-            jobs
+    fn query_jobs(db: &DbConn, query: &str, params: &[&dyn ToSql]) -> Vec<Job> {
+        let conn = db.lock().unwrap();
+        let mut jobs: Vec<Job> = vec![];
+        if let Ok(mut stmt) = conn.prepare_cached(query) {
+            if let Ok(job_iter) = stmt.query_map(params, Job::from_row) {
+                jobs.extend(job_iter.flatten());
+            }
         } else {
             eprintln!("Error: unable to run query");
-            vec![]
         }
-    }
-
-    #[allow(dead_code)]
-    pub fn get_jobs_by_status(status: String) -> Vec<Job> {
-        Job::get_jobs()
-            .into_iter()
-            .filter(|job| job.status == status)
-            .collect()
-    }
-
-    pub fn get_jobs_by_repo(repo: String, branch: String) -> Vec<Job> {
-        Job::get_jobs()
-            .into_iter()
-            .filter(|job| job.repo == repo && job.target_branch == branch)
-            .collect()
+        jobs
     }
 }
 
@@ -232,19 +210,12 @@ pub fn load_env_variables(path: &str) -> rusqlite::Result<(), dotenv::Error> {
 mod tests {
     use crate::database::job::Job;
     use crate::database::SqliteConnection;
-    use crate::util::default_config_path;
-    use rusqlite::params;
-
-    #[test]
-    fn test_insert() {
-        let config_dir = default_config_path().unwrap();
-        let sqlite_path = format!("{}{}", config_dir, "db.sqlite");
-        println!("SQLite database path: {}", sqlite_path);
 
-        let mut job = Job {
+    fn test_job(repo: &str, status: &str) -> Job {
+        Job {
             id: 0,
-            repo: "".to_string(),
-            status: "running".to_string(),
+            repo: repo.to_string(),
+            status: status.to_string(),
             priority: 0,
             created_at: "".to_string(),
             updated_at: "".to_string(),
@@ -253,16 +224,30 @@ mod tests {
             error_message: "".to_string(),
             result: "".to_string(),
             sha: "".to_string(),
-            target_branch: "".to_string(),
-        };
+            target_branch: "main".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_insert() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let mut job = test_job("git@example.com:org/repo", "running");
+
+        job.add_job(&db);
+        job.add_job(&db);
 
-        job.add_job();
+        assert!(Job::check_exists(&db, "git@example.com:org/repo", "main"));
+        assert!(!Job::check_exists(&db, "git@example.com:org/repo", "dev"));
+        assert_eq!(Job::get_jobs(&db).len(), 1);
     }
 
     #[test]
     fn test_read_by_status() {
-        let jobs = Job::get_jobs_by_status("running".to_string());
-        println!("{:?}", jobs);
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        test_job("a", "running").add_job(&db);
+        test_job("b", "idle").add_job(&db);
+        let jobs = Job::get_jobs_by_status(&db, "running");
+        assert_eq!(jobs.len(), 1);
         for job in jobs {
             assert_eq!(job.status, "running");
         }
@@ -270,27 +255,33 @@ mod tests {
 
     #[test]
     pub fn update_sha() {
-        let repo = "git@code.koonts.net:helloimalemur/phantom_ci".to_string();
-        let target_branch = "main".to_string();
-        let sha = "test".to_string();
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let repo = "git@code.koonts.net:helloimalemur/phantom_ci";
+        test_job(repo, "idle").add_job(&db);
 
-        let connection = SqliteConnection::new();
-        let conn = connection.unwrap().conn;
+        assert_eq!(Job::get_sha(&db, repo, "main"), "");
+        Job::update_sha(&db, repo, "main", "test");
+        assert_eq!(Job::get_sha(&db, repo, "main"), "test");
+        assert_eq!(Job::get_jobs_by_repo(&db, repo, "main")[0].sha, "test");
+    }
 
-        match conn.execute(
-            "UPDATE jobs SET sha = ?1 WHERE repo = ?2 AND target_branch = ?3",
-            params![sha, repo, target_branch],
-        ) {
-            Ok(rows_updated) => {
-                if rows_updated > 0 {
-                    println!("SHA Update successful");
-                } else {
-                    println!("No matching job to update");
-                }
-            }
-            Err(error) => {
-                println!("Update error: {}", error);
-            }
-        }
+    #[test]
+    fn test_queued_status() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        test_job("a", "running").add_job(&db);
+        test_job("b", "success").add_job(&db);
+        test_job("c", "idle").add_job(&db);
+        let status = |repo: &str| Job::get_jobs_by_repo(&db, repo, "main")[0].status.clone();
+
+        // A push while a run is going does not hide that run
+        Job::mark_queued(&db, "a", "main");
+        Job::mark_queued(&db, "b", "main");
+        assert_eq!((status("a"), status("b")), ("running".into(), "queued".into()));
+
+        // After a restart only the run that was going is interrupted
+        assert_eq!(Job::interrupt_running(&db), 1);
+        assert_eq!(status("a"), "interrupted");
+        assert_eq!(status("b"), "queued");
+        assert_eq!(status("c"), "idle");
     }
 }
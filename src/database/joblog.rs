@@ -1,6 +1,6 @@
-use crate::database::SqliteConnection;
+use crate::database::DbConn;
 use chrono::Local;
-use rusqlite::params;
+use rusqlite::{params, Row};
 
 #[derive(Debug, Clone)]
 pub struct JobLog {
@@ -10,111 +10,361 @@ pub struct JobLog {
     pub log_message: String,
     #[allow(unused)]
     pub logged_at: String,
+    // Branch, run and step the row belongs to; rows written before these existed have none
+    pub branch: String,
+    pub run_id: Option<i64>,
+    pub step_index: Option<i64>,
+    // 'info', 'warn' or 'error'
+    pub level: String,
 }
 
+// Filters for `phantom_ci logs`; every field is optional
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    // Exact repo URL/path
+    pub repo: Option<String>,
+    // Substring of the repo URL/path
+    pub repo_contains: Option<String>,
+    pub branch: Option<String>,
+    pub run_id: Option<i64>,
+    pub level: Option<String>,
+    // Status of the run the row belongs to
+    pub status: Option<String>,
+    // RFC 3339 bounds on logged_at, `until` exclusive
+    pub since: Option<String>,
+    pub until: Option<String>,
+    // 0 = no limit
+    pub limit: usize,
+}
+
+// A job log row matching `phantom_ci search`, with the matching part of the message
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub log: JobLog,
+    pub snippet: String,
+}
+
+// Rows of the given runs (JSON array of ids), plus rows older than ?2 unless their run is
+// still in progress
+const PRUNABLE_LOGS: &str = "run_id IN (SELECT value FROM json_each(?1))
+    OR (?2 IS NOT NULL AND julianday(logged_at) < julianday(?2)
+        AND (run_id IS NULL OR run_id NOT IN (SELECT id FROM runs WHERE status = 'running')))";
+
+const LOG_COLUMNS: &str = "id, repo, log_message, logged_at, branch, run_id, step_index, level";
+
 impl JobLog {
-    pub fn add_job_log(&mut self) {
-        let connection = SqliteConnection::new();
-        let conn = connection.unwrap().conn;
-
-        match conn.execute(
-            "INSERT INTO job_logs (repo, log_message, logged_at) values (?1, ?2, ?3)",
-            params![self.repo, self.log_message, Local::now().to_rfc3339()],
-        ) {
+    fn from_row(row: &Row) -> rusqlite::Result<JobLog> {
+        Ok(JobLog {
+            id: row.get(0)?,
+            repo: row.get(1)?,
+            log_message: row.get(2)?,
+            logged_at: row.get(3)?,
+            branch: row.get(4)?,
+            run_id: row.get(5)?,
+            step_index: row.get(6)?,
+            level: row.get(7)?,
+        })
+    }
+
+    pub fn add_job_log(&mut self, db: &DbConn) {
+        let conn = db.lock().unwrap();
+
+        match conn.prepare_cached(
+            "INSERT INTO job_logs (repo, log_message, logged_at, branch, run_id, step_index, level) values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )
+        .and_then(|mut stmt| {
+            stmt.execute(params![
+                self.repo,
+                self.log_message,
+                Local::now().to_rfc3339(),
+                self.branch,
+                self.run_id,
+                self.step_index,
+                self.level
+            ])
+        }) {
             Ok(_) => println!("Wrote job log successfully"),
             Err(error) => println!("{}", error),
         }
     }
 
-    // Fetch all logs ordered by newest first
-    #[allow(dead_code)]
-    pub fn get_logs() -> Vec<JobLog> {
-        if let Ok(sql) = SqliteConnection::new() {
-            let mut logs: Vec<JobLog> = vec![];
-            if let Ok(mut stmt) = sql
-                .conn
-                .prepare("SELECT id, repo, log_message, logged_at FROM job_logs ORDER BY logged_at DESC")
+    // Insert a batch of rows in one transaction, keeping each row's own logged_at
+    pub fn add_job_logs(db: &DbConn, logs: &[JobLog]) {
+        let mut conn = db.lock().unwrap();
+        let result = conn.transaction().and_then(|tx| {
             {
-                let job_iter = stmt.query_map([], |row| {
-                    Ok(JobLog {
-                        id: row.get(0)?,
-                        repo: row.get(1)?,
-                        log_message: row.get(2)?,
-                        logged_at: row.get(3)?,
-                    })
-                });
-
-                if let Ok(job_logs_iter) = job_iter {
-                    logs.extend(job_logs_iter.flatten());
+                let mut stmt = tx.prepare_cached(
+                    "INSERT INTO job_logs (repo, log_message, logged_at, branch, run_id, step_index, level) values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                )?;
+                for log in logs {
+                    stmt.execute(params![
+                        log.repo,
+                        log.log_message,
+                        log.logged_at,
+                        log.branch,
+                        log.run_id,
+                        log.step_index,
+                        log.level
+                    ])?;
                 }
             }
-
-            logs
-        } else {
-            eprintln!("Error: unable to run query");
-            vec![]
+            tx.commit()
+        });
+        if let Err(error) = result {
+            println!("{}", error);
         }
     }
 
-    // Fetch newest logs with a limit
-    pub fn get_logs_limited(limit: usize) -> Vec<JobLog> {
-        if let Ok(sql) = SqliteConnection::new() {
-            let mut logs: Vec<JobLog> = vec![];
-            let query = if limit == 0 {
-                "SELECT id, repo, log_message, logged_at FROM job_logs ORDER BY logged_at DESC".to_string()
-            } else {
-                format!(
-                    "SELECT id, repo, log_message, logged_at FROM job_logs ORDER BY logged_at DESC LIMIT {}",
+    // Fetch all logs ordered by newest first
+    #[allow(dead_code)]
+    pub fn get_logs(db: &DbConn) -> Vec<JobLog> {
+        JobLog::get_logs_filtered(db, &LogFilter::default())
+    }
+
+    // Fetch the newest logs matching a filter
+    pub fn get_logs_filtered(db: &DbConn, filter: &LogFilter) -> Vec<JobLog> {
+        let conn = db.lock().unwrap();
+        let mut logs: Vec<JobLog> = vec![];
+        let limit = if filter.limit == 0 { -1 } else { filter.limit as i64 };
+        let query = format!(
+            "SELECT {} FROM job_logs
+            WHERE (?1 IS NULL OR repo = ?1)
+            AND (?2 IS NULL OR instr(repo, ?2) > 0)
+            AND (?3 IS NULL OR branch = ?3)
+            AND (?4 IS NULL OR run_id = ?4)
+            AND (?5 IS NULL OR level = ?5)
+            AND (?6 IS NULL OR run_id IN (SELECT id FROM runs WHERE status = ?6))
+            AND (?7 IS NULL OR julianday(logged_at) >= julianday(?7))
+            AND (?8 IS NULL OR julianday(logged_at) < julianday(?8))
+            ORDER BY logged_at DESC, id DESC LIMIT ?9",
+            LOG_COLUMNS
+        );
+        if let Ok(mut stmt) = conn.prepare_cached(&query) {
+            if let Ok(job_logs_iter) = stmt.query_map(
+                params![
+                    filter.repo,
+                    filter.repo_contains,
+                    filter.branch,
+                    filter.run_id,
+                    filter.level,
+                    filter.status,
+                    filter.since,
+                    filter.until,
                     limit
-                )
-            };
-            if let Ok(mut stmt) = sql.conn.prepare(&query) {
-                let job_iter = stmt.query_map([], |row| {
-                    Ok(JobLog {
-                        id: row.get(0)?,
-                        repo: row.get(1)?,
-                        log_message: row.get(2)?,
-                        logged_at: row.get(3)?,
-                    })
-                });
-                if let Ok(job_logs_iter) = job_iter {
-                    logs.extend(job_logs_iter.flatten());
-                }
+                ],
+                JobLog::from_row,
+            ) {
+                logs.extend(job_logs_iter.flatten());
             }
-            logs
         } else {
             eprintln!("Error: unable to run query");
-            vec![]
         }
+        logs
     }
 
-    // Fetch logs for a repo (exact match) with optional limit
-    pub fn get_logs_by_repo(repo: &str, limit: usize) -> Vec<JobLog> {
-        if let Ok(sql) = SqliteConnection::new() {
-            let mut logs: Vec<JobLog> = vec![];
-            let base = "SELECT id, repo, log_message, logged_at FROM job_logs WHERE repo = ?1 ORDER BY logged_at DESC";
-            let query = if limit == 0 {
-                base.to_string()
-            } else {
-                format!("{} LIMIT {}", base, limit)
-            };
-            if let Ok(mut stmt) = sql.conn.prepare(&query) {
-                let job_iter = stmt.query_map(params![repo], |row| {
-                    Ok(JobLog {
-                        id: row.get(0)?,
-                        repo: row.get(1)?,
-                        log_message: row.get(2)?,
-                        logged_at: row.get(3)?,
-                    })
-                });
-                if let Ok(job_logs_iter) = job_iter {
-                    logs.extend(job_logs_iter.flatten());
-                }
+    // Rows matching every word of `query`, oldest first so the first occurrence leads.
+    // `highlight` wraps each matched term in the snippet.
+    pub fn search(
+        db: &DbConn,
+        query: &str,
+        repo_filter: Option<&str>,
+        since: Option<&str>,
+        limit: usize,
+        highlight: (&str, &str),
+    ) -> Result<Vec<SearchHit>, rusqlite::Error> {
+        let conn = db.lock().unwrap();
+        let limit = if limit == 0 { -1 } else { limit as i64 };
+        let columns = LOG_COLUMNS
+            .split(", ")
+            .map(|c| format!("job_logs.{}", c))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let sql = format!(
+            "SELECT {}, snippet(job_logs_fts, 0, ?5, ?6, '…', 16) FROM job_logs_fts
+            JOIN job_logs ON job_logs.id = job_logs_fts.rowid
+            WHERE job_logs_fts MATCH ?1
+            AND (?2 IS NULL OR instr(job_logs.repo, ?2) > 0)
+            AND (?3 IS NULL OR julianday(job_logs.logged_at) >= julianday(?3))
+            ORDER BY job_logs.logged_at, job_logs.id LIMIT ?4",
+            columns
+        );
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let hits = stmt.query_map(
+            params![
+                fts_query(query),
+                repo_filter,
+                since,
+                limit,
+                highlight.0,
+                highlight.1
+            ],
+            |row| {
+                Ok(SearchHit {
+                    log: JobLog::from_row(row)?,
+                    snippet: row.get(8)?,
+                })
+            },
+        )?;
+        hits.collect()
+    }
+
+    // Rows removed by pruning `run_ids` and everything logged before `cutoff`
+    pub fn count_prunable(db: &DbConn, run_ids: &[i64], cutoff: Option<&str>) -> usize {
+        let conn = db.lock().unwrap();
+        let ids = serde_json::to_string(run_ids).unwrap_or_default();
+        conn.query_row(
+            &format!("SELECT COUNT(*) FROM job_logs WHERE {}", PRUNABLE_LOGS),
+            params![ids, cutoff],
+            |row| row.get::<_, i64>(0),
+        )
+        .map(|n| n as usize)
+        .unwrap_or(0)
+    }
+
+    pub fn delete_prunable(
+        db: &DbConn,
+        run_ids: &[i64],
+        cutoff: Option<&str>,
+    ) -> rusqlite::Result<usize> {
+        let conn = db.lock().unwrap();
+        let ids = serde_json::to_string(run_ids).unwrap_or_default();
+        conn.execute(
+            &format!("DELETE FROM job_logs WHERE {}", PRUNABLE_LOGS),
+            params![ids, cutoff],
+        )
+    }
+
+    // Rows of a run with an id above `after_id`, oldest first
+    pub fn get_logs_after(db: &DbConn, run_id: i64, after_id: i32) -> Vec<JobLog> {
+        let conn = db.lock().unwrap();
+        let mut logs: Vec<JobLog> = vec![];
+        let query = format!(
+            "SELECT {} FROM job_logs WHERE run_id = ?1 AND id > ?2 ORDER BY id",
+            LOG_COLUMNS
+        );
+        if let Ok(mut stmt) = conn.prepare_cached(&query) {
+            if let Ok(job_logs_iter) = stmt.query_map(params![run_id, after_id], JobLog::from_row) {
+                logs.extend(job_logs_iter.flatten());
             }
-            logs
         } else {
             eprintln!("Error: unable to run query");
-            vec![]
         }
+        logs
+    }
+}
+
+// Quote each word so punctuation such as `error[E0599]` or `-` is matched literally
+// instead of being read as FTS5 query syntax; all words must appear in the message
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::run::Run;
+    use crate::database::SqliteConnection;
+
+    fn log(db: &DbConn, branch: &str, run_id: Option<i64>, level: &str, at: &str) {
+        JobLog::add_job_logs(
+            db,
+            &[JobLog {
+                id: 0,
+                repo: "git@example.com:org/repo".to_string(),
+                log_message: format!("{} {}", branch, level),
+                logged_at: at.to_string(),
+                branch: branch.to_string(),
+                run_id,
+                step_index: None,
+                level: level.to_string(),
+            }],
+        );
+    }
+
+    #[test]
+    fn test_filters() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let run = Run::start(&db, "git@example.com:org/repo", "main", "abc", "test", "").unwrap();
+        Run::finish(&db, run, "failed", "", "");
+        log(&db, "main", Some(run), "info", "2024-01-01T10:00:00+00:00");
+        log(&db, "main", Some(run), "error", "2024-01-01T11:00:00+00:00");
+        log(&db, "dev", None, "info", "2024-01-02T10:00:00+02:00");
+
+        let messages = |filter: LogFilter| -> Vec<String> {
+            JobLog::get_logs_filtered(&db, &filter)
+                .into_iter()
+                .map(|l| l.log_message)
+                .collect()
+        };
+        assert_eq!(messages(LogFilter::default()).len(), 3);
+        assert_eq!(
+            messages(LogFilter {
+                branch: Some("main".to_string()),
+                level: Some("error".to_string()),
+                ..Default::default()
+            }),
+            vec!["main error"]
+        );
+        assert_eq!(
+            messages(LogFilter {
+                status: Some("failed".to_string()),
+                limit: 1,
+                ..Default::default()
+            }),
+            vec!["main error"]
+        );
+        // Bounds compare instants, not strings
+        assert_eq!(
+            messages(LogFilter {
+                since: Some("2024-01-01T10:30:00+00:00".to_string()),
+                until: Some("2024-01-02T09:00:00+00:00".to_string()),
+                ..Default::default()
+            }),
+            vec!["dev info", "main error"]
+        );
+        assert_eq!(
+            messages(LogFilter {
+                repo_contains: Some("org/".to_string()),
+                run_id: Some(run),
+                ..Default::default()
+            })
+            .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        log(&db, "main", None, "info", "2024-01-01T10:00:00+00:00");
+        let mut row = JobLog {
+            id: 0,
+            repo: "git@example.com:org/repo".to_string(),
+            log_message: "[step 1] err | error[E0599]: no method named `foo` found".to_string(),
+            logged_at: "2024-01-02T10:00:00+00:00".to_string(),
+            branch: "main".to_string(),
+            run_id: Some(7),
+            step_index: Some(1),
+            level: "info".to_string(),
+        };
+        JobLog::add_job_logs(&db, std::slice::from_ref(&row));
+        row.logged_at = "2024-01-01T09:00:00+00:00".to_string();
+        row.run_id = Some(3);
+        JobLog::add_job_logs(&db, &[row]);
+
+        let hits = JobLog::search(&db, "error[E0599] method", None, None, 0, ("<", ">")).unwrap();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].log.run_id, Some(3));
+        assert!(hits[0].snippet.contains("<method>"), "{}", hits[0].snippet);
+
+        let since = Some("2024-01-01T12:00:00+00:00");
+        let hits = JobLog::search(&db, "e0599", Some("org/repo"), since, 0, ("", "")).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].log.step_index, Some(1));
+        assert!(JobLog::search(&db, "missing", None, None, 0, ("", "")).unwrap().is_empty());
     }
 }
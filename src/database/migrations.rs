@@ -0,0 +1,307 @@
+use anyhow::Error;
+use rusqlite::{Connection, Transaction, TransactionBehavior};
+
+// Ordered schema migrations; the database's `PRAGMA user_version` records how
+// many of them have been applied. Append new entries, never edit old ones.
+//
+// Databases created before versioning existed report version 0 but may already
+// contain the tables from the first migrations, hence `IF NOT EXISTS` there.
+const MIGRATIONS: &[&str] = &[
+    // 1: original jobs/job_logs schema
+    "CREATE TABLE IF NOT EXISTS jobs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        repo TEXT NOT NULL,                  -- Repo of the job (target project)
+        status TEXT NOT NULL,                -- Job status: 'pending', 'running', 'success', 'failed'
+        priority INTEGER DEFAULT 0,          -- Optional priority field for scheduling decisions
+        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,  -- When the job was created
+        updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,  -- Last update timestamp
+        start_time DATETIME,                 -- When the job execution started
+        finish_time DATETIME,                -- When the job execution ended
+        error_message TEXT,                  -- Error message if the job failed
+        result TEXT,                         -- Any result output or summary from the job execution
+        sha TEXT,                            -- Last seen commit for the branch
+        target_branch TEXT                   -- Branch tracked by this job
+    );
+    CREATE TABLE IF NOT EXISTS job_logs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        repo TEXT NOT NULL,                  -- Repo identifier (matches jobs.repo)
+        log_message TEXT NOT NULL,           -- Log message content
+        logged_at DATETIME DEFAULT CURRENT_TIMESTAMP  -- When this log was recorded
+    );",
+    // 2: per-run history
+    "CREATE TABLE IF NOT EXISTS runs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        repo TEXT NOT NULL,                  -- Repo of the run (matches jobs.repo)
+        branch TEXT NOT NULL,                -- Branch the workflow ran for
+        sha TEXT NOT NULL,                   -- Commit the workflow ran against
+        trigger TEXT NOT NULL,               -- Why the run was started
+        start_time DATETIME NOT NULL,        -- When the run started
+        finish_time DATETIME,                -- When the run ended
+        status TEXT NOT NULL,                -- Run status: 'running', 'success', 'failed'
+        error_message TEXT,                  -- Error message if the run failed
+        result TEXT                          -- Summary of the run
+    );
+    CREATE TABLE IF NOT EXISTS run_steps (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+        step_index INTEGER NOT NULL,         -- Workflow step index
+        command TEXT NOT NULL,               -- Command as written in the workflow
+        exit_code INTEGER,                   -- Exit code, NULL if the step failed to start
+        duration_ms INTEGER NOT NULL         -- Wall clock duration of the step
+    );",
+    // 3: indexes for the per-repo/branch lookups done on every poll
+    "CREATE INDEX IF NOT EXISTS idx_jobs_repo_branch ON jobs (repo, target_branch);
+    CREATE INDEX IF NOT EXISTS idx_job_logs_repo ON job_logs (repo, logged_at);
+    CREATE INDEX IF NOT EXISTS idx_runs_repo_branch ON runs (repo, branch);
+    CREATE INDEX IF NOT EXISTS idx_run_steps_run ON run_steps (run_id);",
+    // 4: step outcome ('success', 'failed', 'timed_out', 'error')
+    "ALTER TABLE run_steps ADD COLUMN status TEXT NOT NULL DEFAULT '';",
+    // 5: retried steps get one row per attempt
+    "ALTER TABLE run_steps ADD COLUMN attempt INTEGER NOT NULL DEFAULT 1;",
+    // 6: matrix combination of the run, e.g. 'toolchain=beta' ('' outside a matrix)
+    "ALTER TABLE runs ADD COLUMN matrix TEXT NOT NULL DEFAULT '';",
+    // 7: optional step name from the workflow ('' when unnamed)
+    "ALTER TABLE run_steps ADD COLUMN name TEXT NOT NULL DEFAULT '';",
+    // 8: structured job log rows; level is 'info', 'warn' or 'error'
+    "ALTER TABLE job_logs ADD COLUMN branch TEXT NOT NULL DEFAULT '';
+    ALTER TABLE job_logs ADD COLUMN run_id INTEGER;
+    ALTER TABLE job_logs ADD COLUMN step_index INTEGER;
+    ALTER TABLE job_logs ADD COLUMN level TEXT NOT NULL DEFAULT 'info';
+    CREATE INDEX IF NOT EXISTS idx_job_logs_run ON job_logs (run_id);
+    CREATE INDEX IF NOT EXISTS idx_job_logs_branch ON job_logs (branch, logged_at);",
+    // 9: full-text index over job log messages (every line of step output is a row),
+    // kept in sync by triggers; existing rows are indexed by the rebuild
+    "CREATE VIRTUAL TABLE job_logs_fts USING fts5(log_message, content='job_logs', content_rowid='id');
+    CREATE TRIGGER job_logs_fts_insert AFTER INSERT ON job_logs BEGIN
+        INSERT INTO job_logs_fts (rowid, log_message) VALUES (new.id, new.log_message);
+    END;
+    CREATE TRIGGER job_logs_fts_delete AFTER DELETE ON job_logs BEGIN
+        INSERT INTO job_logs_fts (job_logs_fts, rowid, log_message) VALUES ('delete', old.id, old.log_message);
+    END;
+    CREATE TRIGGER job_logs_fts_update AFTER UPDATE OF log_message ON job_logs BEGIN
+        INSERT INTO job_logs_fts (job_logs_fts, rowid, log_message) VALUES ('delete', old.id, old.log_message);
+        INSERT INTO job_logs_fts (rowid, log_message) VALUES (new.id, new.log_message);
+    END;
+    INSERT INTO job_logs_fts (job_logs_fts) VALUES ('rebuild');",
+    // 10: triggered builds waiting for a worker; rows are deleted when their run ends
+    "CREATE TABLE queue (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        repo TEXT NOT NULL,                  -- Repo of the build (matches jobs.repo)
+        branch TEXT NOT NULL,                -- Branch to build
+        sha TEXT NOT NULL,                   -- Commit seen when the build was queued
+        status TEXT NOT NULL,                -- 'pending' or 'claimed' by a worker
+        enqueued_at DATETIME NOT NULL,       -- When the change was detected
+        claimed_at DATETIME                  -- When a worker took it
+    );
+    CREATE INDEX idx_queue_repo_branch ON queue (repo, branch, status);",
+    // 11: quiet period; a pending build starts no earlier than ready_at (NULL = at once)
+    "ALTER TABLE queue ADD COLUMN ready_at DATETIME;",
+    // 12: branch of log rows written before 8. Workflow messages name it as "<repo>:<branch> "
+    // or "<repo> [<branch>]"; step output rows take the branch of the last such row of the repo.
+    "UPDATE job_logs SET branch = substr(
+            replace(substr(log_message, instr(log_message, repo || ':') + length(repo) + 1), char(10), ' ') || ' ',
+            1,
+            instr(replace(substr(log_message, instr(log_message, repo || ':') + length(repo) + 1), char(10), ' ') || ' ', ' ') - 1)
+        WHERE branch = '' AND instr(log_message, repo || ':') > 0;
+    UPDATE job_logs SET branch = substr(
+            substr(log_message, instr(log_message, repo || ' [') + length(repo) + 2),
+            1,
+            instr(substr(log_message, instr(log_message, repo || ' [') + length(repo) + 2), ']') - 1)
+        WHERE branch = '' AND instr(log_message, repo || ' [') > 0
+            AND instr(substr(log_message, instr(log_message, repo || ' [') + length(repo) + 2), ']') > 1;
+    UPDATE job_logs SET branch = COALESCE((
+            SELECT prev.branch FROM job_logs prev
+            WHERE prev.repo = job_logs.repo AND prev.id < job_logs.id AND prev.branch != ''
+            ORDER BY prev.id DESC LIMIT 1), '')
+        WHERE branch = '';",
+];
+
+// Schema version this binary expects
+pub fn latest_version() -> i64 {
+    MIGRATIONS.len() as i64
+}
+
+pub fn schema_version(conn: &Connection) -> Result<i64, Error> {
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+// Bring the database up to `latest_version()`, one transaction per migration
+pub fn migrate(conn: &Connection) -> Result<(), Error> {
+    migrate_to(conn, latest_version())
+}
+
+fn migrate_to(conn: &Connection, target: i64) -> Result<(), Error> {
+    let current = schema_version(conn)?;
+    let latest = latest_version();
+
+    if current > latest {
+        anyhow::bail!(
+            "database schema version {} is newer than this binary supports ({}); upgrade phantom_ci",
+            current,
+            latest
+        );
+    }
+
+    for (idx, sql) in MIGRATIONS
+        .iter()
+        .enumerate()
+        .take(target as usize)
+        .skip(current as usize)
+    {
+        let version = idx as i64 + 1;
+        // Take the write lock up front so concurrent openers apply each migration once
+        let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+        if schema_version(&tx)? >= version {
+            continue;
+        }
+        tx.execute_batch(sql)
+            .map_err(|e| anyhow::anyhow!("migration {} failed: {}", version, e))?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+        // In-memory databases are rebuilt on every use; only report real upgrades
+        if conn.path().is_some_and(|p| !p.is_empty()) {
+            println!("Applied database migration {}", version);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Schema as created by releases before run history existed
+    const SCHEMA_JOBS_ONLY: &str = "
+        CREATE TABLE jobs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            repo TEXT NOT NULL,
+            status TEXT NOT NULL,
+            priority INTEGER DEFAULT 0,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            start_time DATETIME,
+            finish_time DATETIME,
+            error_message TEXT,
+            result TEXT,
+            sha TEXT,
+            target_branch TEXT
+        );
+        CREATE TABLE job_logs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            repo TEXT NOT NULL,
+            log_message TEXT NOT NULL,
+            logged_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        );
+        INSERT INTO jobs (repo, status, sha, target_branch) VALUES ('git@example.com:org/repo', 'idle', 'abc', 'main');
+        INSERT INTO job_logs (repo, log_message) VALUES ('git@example.com:org/repo', 'hello');";
+
+    // Unversioned schema that already had the runs tables
+    const SCHEMA_WITH_RUNS: &str = "
+        CREATE TABLE runs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            repo TEXT NOT NULL,
+            branch TEXT NOT NULL,
+            sha TEXT NOT NULL,
+            trigger TEXT NOT NULL,
+            start_time DATETIME NOT NULL,
+            finish_time DATETIME,
+            status TEXT NOT NULL,
+            error_message TEXT,
+            result TEXT
+        );
+        CREATE TABLE run_steps (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            run_id INTEGER NOT NULL REFERENCES runs(id) ON DELETE CASCADE,
+            step_index INTEGER NOT NULL,
+            command TEXT NOT NULL,
+            exit_code INTEGER,
+            duration_ms INTEGER NOT NULL
+        );
+        INSERT INTO runs (repo, branch, sha, trigger, start_time, status) VALUES ('git@example.com:org/repo', 'main', 'abc', 'new commit', '2024-01-01T00:00:00+00:00', 'success');";
+
+    fn count(conn: &Connection, table: &str) -> i64 {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn migrates_empty_database() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        assert_eq!(count(&conn, "runs"), 0);
+    }
+
+    #[test]
+    fn migrates_jobs_only_schema() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA_JOBS_ONLY).unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        assert_eq!(count(&conn, "jobs"), 1);
+        assert_eq!(count(&conn, "job_logs"), 1);
+        assert_eq!(count(&conn, "run_steps"), 0);
+    }
+
+    #[test]
+    fn backfills_log_branches() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA_JOBS_ONLY).unwrap();
+        conn.execute_batch(
+            "INSERT INTO job_logs (repo, log_message) VALUES
+                ('git@example.com:org/repo', 'Starting workflow for git@example.com:org/repo [dev] on host'),
+                ('git@example.com:org/repo', '✅ [step 0] make succeeded in 2ms (code Some(0))'),
+                ('git@example.com:org/repo', '❌ Workflow failed for git@example.com:org/repo:release/1.0 after 3ms'),
+                ('git@example.com:org/repo', 'No workflow steps found in main.toml for git@example.com:org/repo:main');",
+        )
+        .unwrap();
+        migrate(&conn).unwrap();
+        let mut stmt = conn.prepare("SELECT branch FROM job_logs ORDER BY id").unwrap();
+        let branches: Vec<String> = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        // The first row predates any message naming a branch
+        assert_eq!(branches, vec!["", "dev", "dev", "release/1.0", "main"]);
+    }
+
+    #[test]
+    fn migrates_schema_with_runs() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA_JOBS_ONLY).unwrap();
+        conn.execute_batch(SCHEMA_WITH_RUNS).unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        assert_eq!(count(&conn, "jobs"), 1);
+        assert_eq!(count(&conn, "runs"), 1);
+    }
+
+    #[test]
+    fn migrates_from_every_version() {
+        for version in 0..=latest_version() {
+            let conn = Connection::open_in_memory().unwrap();
+            migrate_to(&conn, version).unwrap();
+            assert_eq!(schema_version(&conn).unwrap(), version);
+            migrate(&conn).unwrap();
+            assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        }
+    }
+
+    #[test]
+    fn migrate_is_idempotent() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn refuses_newer_database() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", latest_version() + 1)
+            .unwrap();
+        assert!(migrate(&conn).is_err());
+    }
+}
@@ -1,9 +1,35 @@
 use crate::util::default_sqlite_path;
 use anyhow::Error;
 use rusqlite::Connection;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 pub mod job;
 pub mod joblog;
+pub mod migrations;
+pub mod queue;
+pub mod run;
+
+// Connection shared by the whole process; lock it per query, never across an await
+pub type DbConn = Arc<Mutex<Connection>>;
+
+// Bytes used by the database file
+pub fn database_size(db: &DbConn) -> u64 {
+    let conn = db.lock().unwrap();
+    conn.query_row(
+        "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        [],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|n| n as u64)
+    .unwrap_or(0)
+}
+
+// Give the space freed by deletes back to the filesystem
+pub fn vacuum(db: &DbConn) -> rusqlite::Result<()> {
+    let conn = db.lock().unwrap();
+    conn.execute_batch("VACUUM")
+}
 
 pub struct SqliteConnection {
     pub conn: Connection,
@@ -18,47 +44,41 @@ impl SqliteConnection {
         };
 
         let mut sqlite = SqliteConnection { conn };
+        sqlite.configure()?;
 
         if let Err(e) = sqlite.setup_schema() {
             eprintln!("failed to setup schema: {}", e);
+            return Err(e);
         }
 
         Ok(sqlite)
     }
 
-    pub fn setup_schema(&mut self) -> Result<(), anyhow::Error> {
-        if let Err(e) = self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS jobs (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            repo TEXT NOT NULL,           -- Repo of the job (target project)
-            status TEXT NOT NULL,                -- Job status: 'pending', 'running', 'success', 'failed'
-            priority INTEGER DEFAULT 0,          -- Optional priority field for scheduling decisions
-            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,  -- When the job was created
-            updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,  -- Last update timestamp
-            start_time DATETIME,                 -- When the job execution started
-            finish_time DATETIME,                -- When the job execution ended
-            error_message TEXT,                  -- Error message if the job failed
-            result TEXT,                        -- Any result output or summary from the job execution
-            sha TEXT,                        -- Any result output or summary from the job execution
-            target_branch TEXT                  -- Any result output or summary from the job execution
-        )",
-            (),
-        ) {
-            eprintln!("Error: {}", e);
-        }
-
-        if let Err(e) = self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS job_logs (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            repo TEXT NOT NULL,             -- Repo identifier (matches jobs.repo)
-            log_message TEXT NOT NULL,           -- Log message content
-            logged_at DATETIME DEFAULT CURRENT_TIMESTAMP  -- When this log was recorded
-        )",
-            (),
-        ) {
-            eprintln!("Error: {}", e);
-        }
+    // Throwaway database for tests and for `phantom_ci exec` runs that are not recorded
+    pub fn open_in_memory() -> Result<SqliteConnection, Error> {
+        let mut sqlite = SqliteConnection {
+            conn: Connection::open_in_memory()?,
+        };
+        sqlite.configure()?;
+        sqlite.setup_schema()?;
+        Ok(sqlite)
+    }
 
+    // WAL lets CLI readers run alongside the daemon; the busy timeout covers short write overlaps
+    fn configure(&mut self) -> Result<(), Error> {
+        self.conn.busy_timeout(Duration::from_secs(5))?;
+        self.conn
+            .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        self.conn.pragma_update(None, "synchronous", "NORMAL")?;
+        self.conn.pragma_update(None, "foreign_keys", "ON")?;
         Ok(())
     }
+
+    pub fn setup_schema(&mut self) -> Result<(), anyhow::Error> {
+        migrations::migrate(&self.conn)
+    }
+
+    pub fn into_shared(self) -> DbConn {
+        Arc::new(Mutex::new(self.conn))
+    }
 }
@@ -0,0 +1,216 @@
+use crate::database::DbConn;
+use chrono::Local;
+use rusqlite::{params, Row};
+use std::time::Duration;
+
+// A triggered build waiting for, or held by, a worker. Entries are removed once their run
+// finishes, so anything left at startup was interrupted by a restart.
+#[derive(Debug, Clone)]
+pub struct QueueEntry {
+    pub id: i64,
+    pub repo: String,
+    pub branch: String,
+    pub sha: String,
+    // 'pending' until a worker claims it, then 'claimed'
+    pub status: String,
+    pub enqueued_at: String,
+    pub claimed_at: String,
+    // Start no earlier than this; empty when there is no quiet period
+    pub ready_at: String,
+}
+
+const QUEUE_COLUMNS: &str = "id, repo, branch, sha, status, enqueued_at, claimed_at, ready_at";
+// A pending entry whose quiet period is over, with ?1 the current time
+const READY: &str = "(ready_at IS NULL OR julianday(ready_at) <= julianday(?1))";
+
+impl QueueEntry {
+    fn from_row(row: &Row) -> rusqlite::Result<QueueEntry> {
+        Ok(QueueEntry {
+            id: row.get(0)?,
+            repo: row.get(1)?,
+            branch: row.get(2)?,
+            sha: row.get(3)?,
+            status: row.get(4)?,
+            enqueued_at: row.get(5)?,
+            claimed_at: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+            ready_at: row.get::<_, Option<String>>(7)?.unwrap_or_default(),
+        })
+    }
+
+    // Queue a build of `branch`; a build of it still waiting is moved to the new SHA instead,
+    // since it checks out the branch tip anyway. With a quiet period the build waits until the
+    // branch has not moved for that long. Returns the entry id and whether it is new.
+    pub fn enqueue(
+        db: &DbConn,
+        repo: &str,
+        branch: &str,
+        sha: &str,
+        quiet_period: Option<Duration>,
+    ) -> Result<(i64, bool), anyhow::Error> {
+        let now = Local::now();
+        let ready_at = match quiet_period {
+            Some(quiet) => Some(
+                chrono::Duration::from_std(quiet)
+                    .ok()
+                    .and_then(|quiet| now.checked_add_signed(quiet))
+                    .ok_or_else(|| anyhow::anyhow!("quiet period {:?} is too long", quiet))?
+                    .to_rfc3339(),
+            ),
+            None => None,
+        };
+        let conn = db.lock().unwrap();
+        let waiting: Option<i64> = conn
+            .query_row(
+                "SELECT id FROM queue WHERE repo = ?1 AND branch = ?2 AND status = 'pending'",
+                params![repo, branch],
+                |row| row.get(0),
+            )
+            .ok();
+        if let Some(id) = waiting {
+            conn.execute(
+                "UPDATE queue SET sha = ?1, ready_at = ?2 WHERE id = ?3",
+                params![sha, ready_at, id],
+            )?;
+            return Ok((id, false));
+        }
+        conn.execute(
+            "INSERT INTO queue (repo, branch, sha, status, enqueued_at, ready_at) VALUES (?1, ?2, ?3, 'pending', ?4, ?5)",
+            params![repo, branch, sha, now.to_rfc3339(), ready_at],
+        )?;
+        Ok((conn.last_insert_rowid(), true))
+    }
+
+    // Take a pending entry for a worker; false if it is gone, already claimed, or a newer push
+    // restarted its quiet period
+    pub fn claim(db: &DbConn, id: i64) -> bool {
+        let conn = db.lock().unwrap();
+        let sql = format!(
+            "UPDATE queue SET status = 'claimed', claimed_at = ?1 WHERE id = ?2 AND status = 'pending' AND {}",
+            READY
+        );
+        conn.execute(&sql, params![Local::now().to_rfc3339(), id])
+            .map(|n| n == 1)
+            .unwrap_or(false)
+    }
+
+    // Drop an entry once its run is over
+    pub fn complete(db: &DbConn, id: i64) {
+        let conn = db.lock().unwrap();
+        if let Err(e) = conn.execute("DELETE FROM queue WHERE id = ?1", params![id]) {
+            println!("Delete error: {}", e);
+        }
+    }
+
+    // Put entries claimed by a process that is no longer running back in the queue
+    pub fn requeue_claimed(db: &DbConn) -> usize {
+        let conn = db.lock().unwrap();
+        conn.execute(
+            "UPDATE queue SET status = 'pending', claimed_at = NULL WHERE status = 'claimed'",
+            [],
+        )
+        .unwrap_or(0)
+    }
+
+    // Forget entries claimed by a process that is no longer running
+    pub fn remove_claimed(db: &DbConn) -> usize {
+        let conn = db.lock().unwrap();
+        conn.execute("DELETE FROM queue WHERE status = 'claimed'", [])
+            .unwrap_or(0)
+    }
+
+    // Every entry, oldest first
+    pub fn get_all(db: &DbConn) -> Vec<QueueEntry> {
+        let conn = db.lock().unwrap();
+        let sql = format!("SELECT {} FROM queue ORDER BY id", QUEUE_COLUMNS);
+        conn.prepare(&sql)
+            .and_then(|mut stmt| {
+                stmt.query_map([], QueueEntry::from_row)?
+                    .collect::<rusqlite::Result<Vec<QueueEntry>>>()
+            })
+            .unwrap_or_default()
+    }
+
+    // Pending entries whose quiet period is over, oldest first
+    pub fn get_ready(db: &DbConn) -> Vec<QueueEntry> {
+        let conn = db.lock().unwrap();
+        let sql = format!(
+            "SELECT {} FROM queue WHERE status = 'pending' AND {} ORDER BY id",
+            QUEUE_COLUMNS, READY
+        );
+        conn.prepare(&sql)
+            .and_then(|mut stmt| {
+                stmt.query_map(params![Local::now().to_rfc3339()], QueueEntry::from_row)?
+                    .collect::<rusqlite::Result<Vec<QueueEntry>>>()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SqliteConnection;
+
+    #[test]
+    fn test_queue_lifecycle() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let (first, new) = QueueEntry::enqueue(&db, "repo", "main", "aaa", None).unwrap();
+        assert!(new);
+        // A second push while the first build waits moves it to the newer commit
+        let (again, new) = QueueEntry::enqueue(&db, "repo", "main", "bbb", None).unwrap();
+        assert_eq!((again, new), (first, false));
+        let (dev, _) = QueueEntry::enqueue(&db, "repo", "dev", "ccc", None).unwrap();
+
+        assert!(QueueEntry::claim(&db, first));
+        assert!(!QueueEntry::claim(&db, first));
+        // Once claimed, a new push queues another build behind it
+        let (next, new) = QueueEntry::enqueue(&db, "repo", "main", "ddd", None).unwrap();
+        assert!(new && next != first);
+        let pending: Vec<i64> = QueueEntry::get_ready(&db).iter().map(|e| e.id).collect();
+        assert_eq!(pending, vec![dev, next]);
+
+        // After a restart the claimed build is pending again, at its place in line
+        assert_eq!(QueueEntry::requeue_claimed(&db), 1);
+        let pending: Vec<(i64, String)> = QueueEntry::get_ready(&db)
+            .into_iter()
+            .map(|e| (e.id, e.sha))
+            .collect();
+        assert_eq!(pending[0], (first, "bbb".to_string()));
+
+        QueueEntry::complete(&db, first);
+        assert_eq!(QueueEntry::get_all(&db).len(), 2);
+    }
+
+    #[test]
+    fn test_quiet_period() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let quiet = Some(Duration::from_secs(120));
+        let (id, _) = QueueEntry::enqueue(&db, "repo", "main", "aaa", quiet).unwrap();
+        QueueEntry::enqueue(&db, "repo", "dev", "bbb", None).unwrap();
+        let ready: Vec<String> = QueueEntry::get_ready(&db)
+            .into_iter()
+            .map(|e| e.branch)
+            .collect();
+        assert_eq!(ready, vec!["dev"]);
+        assert!(!QueueEntry::claim(&db, id));
+
+        // Another push restarts the wait; without one the latest commit builds once it is over
+        QueueEntry::enqueue(&db, "repo", "main", "ccc", quiet).unwrap();
+        db.lock()
+            .unwrap()
+            .execute(
+                "UPDATE queue SET ready_at = ?1 WHERE id = ?2",
+                params![Local::now().to_rfc3339(), id],
+            )
+            .unwrap();
+        let ready: Vec<String> = QueueEntry::get_ready(&db)
+            .into_iter()
+            .map(|e| e.sha)
+            .collect();
+        assert_eq!(ready, vec!["ccc", "bbb"]);
+        assert!(QueueEntry::claim(&db, id));
+
+        let forever = Some(Duration::from_secs(100_000_000 * 24 * 3600));
+        assert!(QueueEntry::enqueue(&db, "repo", "main", "ddd", forever).is_err());
+    }
+}
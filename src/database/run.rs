@@ -0,0 +1,325 @@
+use crate::database::DbConn;
+use chrono::Local;
+use rusqlite::{params, Row};
+
+// A single execution of a workflow for a repo/branch
+#[derive(Debug, Clone)]
+pub struct Run {
+    pub id: i64,
+    pub repo: String,
+    pub branch: String,
+    pub sha: String,
+    pub trigger: String,
+    pub start_time: String,
+    pub finish_time: String,
+    pub status: String,
+    pub error_message: String,
+    pub result: String,
+    pub matrix: String,
+}
+
+// Outcome of one workflow step within a run
+#[derive(Debug, Clone)]
+pub struct RunStep {
+    #[allow(unused)]
+    pub id: i64,
+    #[allow(unused)]
+    pub run_id: i64,
+    pub step_index: i64,
+    pub command: String,
+    pub name: String,
+    pub exit_code: Option<i32>,
+    pub duration_ms: i64,
+    pub status: String,
+    pub attempt: i64,
+}
+
+const RUN_COLUMNS: &str =
+    "id, repo, branch, sha, trigger, start_time, finish_time, status, error_message, result, matrix";
+
+impl Run {
+    fn from_row(row: &Row) -> rusqlite::Result<Run> {
+        Ok(Run {
+            id: row.get(0)?,
+            repo: row.get(1)?,
+            branch: row.get(2)?,
+            sha: row.get(3)?,
+            trigger: row.get(4)?,
+            start_time: row.get(5)?,
+            finish_time: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+            status: row.get(7)?,
+            error_message: row.get::<_, Option<String>>(8)?.unwrap_or_default(),
+            result: row.get::<_, Option<String>>(9)?.unwrap_or_default(),
+            matrix: row.get(10)?,
+        })
+    }
+
+    // Insert a new run in the 'running' state and return its id
+    pub fn start(
+        db: &DbConn,
+        repo: &str,
+        branch: &str,
+        sha: &str,
+        trigger: &str,
+        matrix: &str,
+    ) -> Option<i64> {
+        let conn = db.lock().unwrap();
+
+        match conn.execute(
+            "INSERT INTO runs (repo, branch, sha, trigger, start_time, status, matrix) values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![repo, branch, sha, trigger, Local::now().to_rfc3339(), "running", matrix],
+        ) {
+            Ok(_) => Some(conn.last_insert_rowid()),
+            Err(error) => {
+                println!("Insert error: {}", error);
+                None
+            }
+        }
+    }
+
+    pub fn finish(db: &DbConn, id: i64, status: &str, error_message: &str, result: &str) {
+        let conn = db.lock().unwrap();
+
+        match conn.execute(
+            "UPDATE runs SET status = ?1, error_message = ?2, result = ?3, finish_time = ?4 WHERE id = ?5",
+            params![status, error_message, result, Local::now().to_rfc3339(), id],
+        ) {
+            Ok(rows_updated) => {
+                if rows_updated == 0 {
+                    println!("No matching run to update");
+                }
+            }
+            Err(error) => {
+                println!("Update error: {}", error);
+            }
+        }
+    }
+
+    // Close runs a previous daemon left 'running'; returns their ids
+    pub fn interrupt_running(db: &DbConn, error_message: &str) -> Vec<i64> {
+        let conn = db.lock().unwrap();
+        let ids: Vec<i64> = conn
+            .prepare("SELECT id FROM runs WHERE status = 'running' ORDER BY id")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<i64>>>()
+            })
+            .unwrap_or_default();
+        if let Err(error) = conn.execute(
+            "UPDATE runs SET status = 'interrupted', error_message = ?1, finish_time = ?2 WHERE status = 'running'",
+            params![error_message, Local::now().to_rfc3339()],
+        ) {
+            println!("Update error: {}", error);
+            return vec![];
+        }
+        ids
+    }
+
+    pub fn get_run(db: &DbConn, id: i64) -> Option<Run> {
+        let conn = db.lock().unwrap();
+        let query = format!("SELECT {} FROM runs WHERE id = ?1", RUN_COLUMNS);
+        conn.query_row(&query, params![id], Run::from_row).ok()
+    }
+
+    // Newest run still in progress, optionally filtered by exact repo or a repo substring
+    pub fn get_running(
+        db: &DbConn,
+        repo: Option<&str>,
+        repo_contains: Option<&str>,
+    ) -> Option<Run> {
+        let conn = db.lock().unwrap();
+        let query = format!(
+            "SELECT {} FROM runs WHERE status = 'running' AND (?1 IS NULL OR repo = ?1) AND (?2 IS NULL OR instr(repo, ?2) > 0) ORDER BY id DESC LIMIT 1",
+            RUN_COLUMNS
+        );
+        conn.query_row(&query, params![repo, repo_contains], Run::from_row).ok()
+    }
+
+    // Fetch newest runs first, optionally filtered by a repo substring (0 = no limit)
+    pub fn get_runs(db: &DbConn, repo_filter: Option<&str>, limit: usize) -> Vec<Run> {
+        let conn = db.lock().unwrap();
+        let mut runs: Vec<Run> = vec![];
+        let query = format!(
+            "SELECT {} FROM runs WHERE (?1 IS NULL OR instr(repo, ?1) > 0) ORDER BY id DESC LIMIT ?2",
+            RUN_COLUMNS
+        );
+        let limit = if limit == 0 { -1 } else { limit as i64 };
+        if let Ok(mut stmt) = conn.prepare_cached(&query) {
+            if let Ok(run_iter) = stmt.query_map(params![repo_filter, limit], Run::from_row) {
+                runs.extend(run_iter.flatten());
+            }
+        } else {
+            eprintln!("Error: unable to run query");
+        }
+        runs
+    }
+
+    // Finished runs started before `cutoff` (RFC 3339)
+    pub fn finished_before(db: &DbConn, cutoff: &str) -> Vec<i64> {
+        run_ids(
+            db,
+            "SELECT id FROM runs WHERE status != 'running' AND julianday(start_time) < julianday(?1) ORDER BY id",
+            params![cutoff],
+        )
+    }
+
+    // Finished runs beyond the newest `keep` runs of their repo/branch
+    pub fn beyond_newest(db: &DbConn, keep: usize) -> Vec<i64> {
+        run_ids(
+            db,
+            "SELECT id FROM (
+                SELECT id, status, ROW_NUMBER() OVER (PARTITION BY repo, branch ORDER BY id DESC) AS n FROM runs
+            ) WHERE n > ?1 AND status != 'running' ORDER BY id",
+            params![keep as i64],
+        )
+    }
+
+    // Finished runs, oldest first, with the size of their job log messages
+    pub fn finished_log_bytes(db: &DbConn) -> Vec<(i64, u64)> {
+        let conn = db.lock().unwrap();
+        let mut runs = vec![];
+        if let Ok(mut stmt) = conn.prepare_cached(
+            "SELECT id, (SELECT COALESCE(SUM(length(log_message)), 0) FROM job_logs WHERE run_id = runs.id)
+            FROM runs WHERE status != 'running' ORDER BY id",
+        ) {
+            if let Ok(rows) = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))) {
+                runs.extend(rows.flatten());
+            }
+        } else {
+            eprintln!("Error: unable to run query");
+        }
+        runs
+    }
+
+    // Delete runs; their steps go with them
+    pub fn delete_runs(db: &DbConn, ids: &[i64]) -> rusqlite::Result<usize> {
+        let conn = db.lock().unwrap();
+        conn.execute(
+            "DELETE FROM runs WHERE id IN (SELECT value FROM json_each(?1))",
+            params![serde_json::to_string(ids).unwrap_or_default()],
+        )
+    }
+}
+
+fn run_ids(db: &DbConn, query: &str, params: impl rusqlite::Params) -> Vec<i64> {
+    let conn = db.lock().unwrap();
+    let mut ids = vec![];
+    if let Ok(mut stmt) = conn.prepare_cached(query) {
+        if let Ok(rows) = stmt.query_map(params, |row| row.get::<_, i64>(0)) {
+            ids.extend(rows.flatten());
+        }
+    } else {
+        eprintln!("Error: unable to run query");
+    }
+    ids
+}
+
+impl RunStep {
+    pub fn add_step(&mut self, db: &DbConn) {
+        let conn = db.lock().unwrap();
+
+        match conn.execute(
+            "INSERT INTO run_steps (run_id, step_index, command, exit_code, duration_ms, status, attempt, name) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![self.run_id, self.step_index, self.command, self.exit_code, self.duration_ms, self.status, self.attempt, self.name],
+        ) {
+            Ok(_) => self.id = conn.last_insert_rowid(),
+            Err(error) => println!("Insert error: {}", error),
+        }
+    }
+
+    pub fn get_steps(db: &DbConn, run_id: i64) -> Vec<RunStep> {
+        let conn = db.lock().unwrap();
+        let mut steps: Vec<RunStep> = vec![];
+        if let Ok(mut stmt) = conn.prepare_cached(
+            "SELECT id, run_id, step_index, command, exit_code, duration_ms, status, attempt, name FROM run_steps WHERE run_id = ?1 ORDER BY id",
+        ) {
+            let step_iter = stmt.query_map(params![run_id], |row| {
+                Ok(RunStep {
+                    id: row.get(0)?,
+                    run_id: row.get(1)?,
+                    step_index: row.get(2)?,
+                    command: row.get(3)?,
+                    exit_code: row.get(4)?,
+                    duration_ms: row.get(5)?,
+                    status: row.get(6)?,
+                    attempt: row.get(7)?,
+                    name: row.get(8)?,
+                })
+            });
+            if let Ok(step_iter) = step_iter {
+                steps.extend(step_iter.flatten());
+            }
+        } else {
+            eprintln!("Error: unable to run query");
+        }
+        steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::run::{Run, RunStep};
+    use crate::database::SqliteConnection;
+
+    #[test]
+    fn test_run_history() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let id = Run::start(&db, "test/run-history", "main", "abc123", "test", "toolchain=beta").unwrap();
+        for (idx, command, code, status) in
+            [(0, "echo hi", 0, "success"), (1, "false", 1, "failed")]
+        {
+            let mut step = RunStep {
+                id: 0,
+                run_id: id,
+                step_index: idx,
+                command: command.to_string(),
+                name: String::new(),
+                exit_code: Some(code),
+                duration_ms: 5,
+                status: status.to_string(),
+                attempt: 1,
+            };
+            step.add_step(&db);
+            assert!(step.id > 0);
+        }
+        Run::finish(&db, id, "failed", "step 1 failed", "");
+
+        let run = Run::get_run(&db, id).unwrap();
+        assert_eq!(run.status, "failed");
+        assert_eq!(run.error_message, "step 1 failed");
+        assert_eq!(run.matrix, "toolchain=beta");
+        assert!(!run.finish_time.is_empty());
+
+        let steps = RunStep::get_steps(&db, id);
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[1].exit_code, Some(1));
+        assert_eq!(steps[1].status, "failed");
+        assert!(Run::get_runs(&db, Some("test/run-history"), 0).iter().any(|r| r.id == id));
+    }
+
+    #[test]
+    fn test_interrupt_running() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let done = Run::start(&db, "test/interrupt", "main", "abc", "test", "").unwrap();
+        Run::finish(&db, done, "success", "", "");
+        let running = Run::start(&db, "test/interrupt", "dev", "def", "test", "").unwrap();
+
+        assert_eq!(Run::interrupt_running(&db, "daemon stopped"), vec![running]);
+        assert_eq!(Run::get_run(&db, running).unwrap().status, "interrupted");
+        assert_eq!(Run::get_run(&db, done).unwrap().status, "success");
+        assert!(Run::interrupt_running(&db, "daemon stopped").is_empty());
+    }
+
+    #[test]
+    fn test_get_running_repo_filters() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let fork = Run::start(&db, "org/app-fork", "main", "abc", "test", "").unwrap();
+        let app = Run::start(&db, "org/app", "main", "def", "test", "").unwrap();
+        Run::finish(&db, app, "success", "", "");
+
+        assert!(Run::get_running(&db, Some("org/app"), None).is_none());
+        assert_eq!(Run::get_running(&db, None, Some("org/app")).unwrap().id, fork);
+        assert_eq!(Run::get_running(&db, Some("org/app-fork"), None).unwrap().id, fork);
+        assert_eq!(Run::get_running(&db, None, None).unwrap().id, fork);
+    }
+}
@@ -1,6 +1,8 @@
 // logging.rs
 use log::{error, info};
 
+pub mod runlog;
+
 pub fn init() {
     env_logger::init();
 }
@@ -0,0 +1,179 @@
+use crate::util::default_repo_work_path;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+// Complete output of every step of one run, kept under <cache>/.logs/run-<id>/:
+//   step-N.log     written while the step runs
+//   step-N.log.gz  the raw log, compressed once the run has finished
+//   step-N.txt     ANSI-stripped copy used for notifications and `logs --run`
+#[derive(Debug, Clone)]
+pub struct RunLogs {
+    dir: PathBuf,
+}
+
+impl RunLogs {
+    pub fn for_run(run_id: i64) -> Option<RunLogs> {
+        Some(RunLogs::in_dir(logs_root()?.join(format!("run-{}", run_id))))
+    }
+
+    // Every run directory under the cache dir, by run id
+    pub fn all() -> Vec<(i64, RunLogs)> {
+        let Some(root) = logs_root() else {
+            return vec![];
+        };
+        let mut runs: Vec<(i64, RunLogs)> = fs::read_dir(root)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter_map(|e| {
+                        let name = e.file_name().to_string_lossy().to_string();
+                        let id = name.strip_prefix("run-")?.parse().ok()?;
+                        Some((id, RunLogs::in_dir(e.path())))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        runs.sort_by_key(|(id, _)| *id);
+        runs
+    }
+
+    pub fn in_dir(dir: PathBuf) -> RunLogs {
+        RunLogs { dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, step: usize, ext: &str) -> PathBuf {
+        self.dir.join(format!("step-{}.{}", step, ext))
+    }
+
+    // Start from an empty directory; run ids restart when the database is recreated
+    pub fn create(&self) -> io::Result<()> {
+        if self.dir.exists() {
+            fs::remove_dir_all(&self.dir)?;
+        }
+        fs::create_dir_all(&self.dir)
+    }
+
+    // Open a step's live log for appending; retries append to the same file
+    pub fn append(&self, step: usize) -> io::Result<File> {
+        fs::create_dir_all(&self.dir)?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(step, "log"))
+    }
+
+    // Compress each live log and write its ANSI-stripped copy
+    pub fn finish(&self) -> io::Result<()> {
+        for step in self.steps() {
+            let live = self.path(step, "log");
+            if !live.exists() {
+                continue;
+            }
+            let raw = fs::read(&live)?;
+            fs::write(self.path(step, "txt"), strip_ansi_escapes::strip(&raw))?;
+            let mut encoder = GzEncoder::new(
+                File::create(self.path(step, "log.gz"))?,
+                Compression::default(),
+            );
+            encoder.write_all(&raw)?;
+            encoder.finish()?;
+            fs::remove_file(&live)?;
+        }
+        Ok(())
+    }
+
+    // Bytes used by the run's log files
+    pub fn size(&self) -> u64 {
+        fs::read_dir(&self.dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter_map(|e| e.metadata().ok())
+                    .map(|m| m.len())
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    pub fn remove(&self) -> io::Result<()> {
+        fs::remove_dir_all(&self.dir)
+    }
+
+    // Indexes of the steps that have a log, in order
+    pub fn steps(&self) -> Vec<usize> {
+        let mut steps: Vec<usize> = fs::read_dir(&self.dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter_map(|e| {
+                        let name = e.file_name().to_string_lossy().to_string();
+                        let rest = name.strip_prefix("step-")?;
+                        rest[..rest.find('.')?].parse().ok()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        steps.sort();
+        steps.dedup();
+        steps
+    }
+
+    // A step's log, with escape sequences unless stripped. A live log is read while
+    // its run is still going.
+    pub fn read(&self, step: usize, raw: bool) -> io::Result<String> {
+        let live = self.path(step, "log");
+        let bytes = if live.exists() {
+            let bytes = fs::read(&live)?;
+            if raw {
+                bytes
+            } else {
+                strip_ansi_escapes::strip(&bytes)
+            }
+        } else if raw {
+            let mut bytes = vec![];
+            GzDecoder::new(File::open(self.path(step, "log.gz"))?).read_to_end(&mut bytes)?;
+            bytes
+        } else {
+            fs::read(self.path(step, "txt"))?
+        };
+        Ok(String::from_utf8_lossy(&bytes).to_string())
+    }
+}
+
+fn logs_root() -> Option<PathBuf> {
+    default_repo_work_path(".logs".to_string()).map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finish_and_read() {
+        let logs = RunLogs::in_dir(std::env::temp_dir().join("phantom_ci-test-runlog"));
+        logs.create().unwrap();
+        let colored = "12:00:00.000 out | \x1b[31mred\x1b[0m\n";
+        logs.append(2).unwrap().write_all(colored.as_bytes()).unwrap();
+        logs.append(0).unwrap().write_all(b"one\n").unwrap();
+        logs.append(0).unwrap().write_all(b"two\n").unwrap();
+
+        assert_eq!(logs.steps(), vec![0, 2]);
+        assert_eq!(logs.read(2, false).unwrap(), "12:00:00.000 out | red\n");
+
+        logs.finish().unwrap();
+        assert!(!logs.path(2, "log").exists());
+        assert_eq!(logs.steps(), vec![0, 2]);
+        assert_eq!(logs.read(0, false).unwrap(), "one\ntwo\n");
+        assert_eq!(logs.read(2, true).unwrap(), colored);
+        assert_eq!(logs.read(2, false).unwrap(), "12:00:00.000 out | red\n");
+        let _ = fs::remove_dir_all(logs.dir());
+    }
+}
@@ -4,6 +4,7 @@ pub mod logging;
 pub mod options;
 pub mod parser;
 pub mod repo;
+pub mod settings;
 pub mod util;
 pub mod webhook;
 
@@ -21,16 +21,94 @@ pub enum Command {
         /// Filter by exact repo URL/path
         #[arg(long)]
         repo: Option<String>,
-        /// Filter by branch name (best-effort; matches inside message)
+        /// Filter by branch name
         #[arg(long)]
         branch: Option<String>,
+        /// Only rows of runs with this status (e.g. failed)
+        #[arg(long)]
+        status: Option<String>,
+        /// Only rows at or after this time (RFC 3339, "YYYY-MM-DD [HH:MM:SS]" or an age like 2h)
+        #[arg(long)]
+        since: Option<String>,
+        /// Only rows before this time (same formats as --since)
+        #[arg(long)]
+        until: Option<String>,
+        /// Filter by level
+        #[arg(long, value_parser = ["info", "warn", "error"])]
+        level: Option<String>,
         /// Limit number of log rows (0 = no limit)
         #[arg(long, default_value_t = 50)]
         limit: usize,
+        /// Print the full step logs of a run (id as listed by `jobs`); with other
+        /// filters, list all of that run's log rows instead
+        #[arg(long, conflicts_with_all = ["sub", "repo", "limit"])]
+        run: Option<i64>,
+        /// Only this step of --run
+        #[arg(long, requires = "run")]
+        step: Option<usize>,
+        /// Keep color codes and other escape sequences
+        #[arg(long, requires = "run")]
+        raw: bool,
+        /// Print the running job's log as it is written; exits with the run's status
+        #[arg(long, conflicts_with = "run")]
+        follow: bool,
+    },
+    Search {
+        /// Words that must all appear in a log line
+        query: String,
+        /// Filter by substring of repo URL/name
+        #[arg(long)]
+        repo: Option<String>,
+        /// Only rows at or after this time (RFC 3339, "YYYY-MM-DD [HH:MM:SS]" or an age like 2h)
+        #[arg(long)]
+        since: Option<String>,
+        /// Limit number of results (0 = no limit)
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
     },
     Jobs {
+        /// Filter by substring of repo URL/name
+        sub: Option<String>,
+        /// Limit number of runs (0 = no limit)
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    Queue {
+        /// Filter by substring of repo URL/name
         sub: Option<String>,
     },
+    Show {
+        /// Run id as listed by `jobs`
+        run_id: i64,
+    },
+    Exec {
+        /// Workflow file (default: workflow/<branch>.toml, then workflow/main.toml)
+        #[arg(long)]
+        workflow: Option<String>,
+        /// Local checkout to run in
+        #[arg(long, default_value = ".")]
+        dir: String,
+        /// Record the run in the job history
+        #[arg(long)]
+        record: bool,
+        /// Send webhook notifications
+        #[arg(long)]
+        notify: bool,
+    },
+    Validate {
+        /// Workflow files to check
+        #[arg(required = true)]
+        workflows: Vec<String>,
+    },
+    Graph {
+        /// Path to a workflow file
+        workflow: String,
+    },
+    Prune {
+        /// Report what would be deleted without deleting anything
+        #[arg(long)]
+        dry_run: bool,
+    },
     Repo {
         sub: Option<String>,
     },
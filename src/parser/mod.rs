@@ -1,193 +1,748 @@
 use crate::database::job::Job;
 use crate::database::joblog::JobLog;
+use crate::database::run::Run;
+use crate::database::DbConn;
+use crate::logging::runlog::RunLogs;
+use crate::parser::context::{matrix_collisions, RunContext};
+use crate::parser::runner::StepRunner;
+use crate::parser::scheduler::{run_graph, GraphResult, StepGraph, DEFAULT_MAX_PARALLEL};
+use crate::parser::validate::load_workflow;
 use crate::repo::Repo;
 use chrono::Local;
-use config::Config;
 use log::{error, info, warn};
 use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
 use std::time::Instant;
-use tokio::process::Command;
 use tokio::sync::mpsc::Sender;
 use whoami::hostname;
 
-// Public entry point: parse workflow and run steps sequentially with fail-fast semantics
-pub async fn parse_workflow(file_path: &str, repo: Repo, tx_clone: Sender<String>) {
-    let host = hostname().unwrap_or_default();
-    let starting_message = format!(
-        "Starting workflow for {} [{}] on {}",
-        repo.path, repo.target_branch, host
-    );
-    info!("{}", starting_message);
-    println!("{}", starting_message);
-    repo.send_webhook(starting_message.clone(), &repo).await;
-
-    // Load workflow commands and order by numeric key
-    let commands = get_command_from_config(file_path.to_string());
-    let mut ordered = BTreeMap::<usize, WorkflowCommand>::new();
-    for (k, v) in commands.into_iter() {
-        match k.parse::<usize>() {
-            Ok(i) => {
-                ordered.insert(i, v);
-            }
-            Err(_) => {
-                warn!("Ignoring non-numeric workflow key '{}' in {}", k, file_path);
-            }
+pub use cancel::CancelToken;
+
+mod cancel;
+mod context;
+mod runner;
+mod scheduler;
+mod step;
+mod validate;
+
+// How a workflow run reports its progress
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    // Send webhook notifications
+    pub notify: bool,
+    // Keep each step's full output under the cache dir
+    pub log_files: bool,
+    // Stops the run early; its reason becomes the run's status
+    pub cancel: CancelToken,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            notify: true,
+            log_files: true,
+            cancel: CancelToken::default(),
         }
     }
+}
+
+// Public entry point: parse workflow and run its step graph with fail-fast semantics,
+// once per [matrix] combination. Returns whether every run succeeded.
+pub async fn parse_workflow(
+    file_path: &str,
+    repo: Repo,
+    trigger: &str,
+    db: &DbConn,
+    tx_clone: Sender<String>,
+    options: RunOptions,
+) -> bool {
+    let host = hostname().unwrap_or_default();
 
-    if ordered.is_empty() {
+    // Validate and load the workflow; problems fail the run with the real error
+    let (workflow, ordered, graph) = match load_workflow(file_path) {
+        Ok(workflow) => {
+            let ordered = ordered_steps(&workflow);
+            let graph = StepGraph::build(&ordered);
+            (workflow, ordered, graph)
+        }
+        Err(problems) => (Workflow::default(), BTreeMap::new(), Err(problems.join("\n  - "))),
+    };
+    // Colliding keys are caught by validation, but never let one value silently replace another
+    let (combinations, graph) = match matrix_combinations(&workflow.matrix) {
+        Ok(combinations) => (combinations, graph),
+        Err(e) => (vec![BTreeMap::new()], Err(e)),
+    };
+    let graph = graph.map_err(|e| {
+        format!(
+            "Invalid workflow {} for {}:{}:\n  - {}",
+            file_path, repo.path, repo.target_branch, e
+        )
+    });
+    let is_matrix = !workflow.matrix.is_empty();
+
+    if is_matrix {
         let msg = format!(
-            "No workflow steps found in {} for {}:{}",
-            file_path, repo.path, repo.target_branch
+            "Starting matrix workflow for {} [{}] on {} ({} combinations)",
+            repo.path,
+            repo.target_branch,
+            host,
+            combinations.len()
         );
-        warn!("{}", msg);
+        info!("{}", msg);
         println!("{}", msg);
-        Job::update_status(repo.path.clone(), repo.target_branch.clone(), "failed".to_string());
-        Job::update_finished_time(repo.path.clone(), repo.target_branch.clone());
-        let mut log = JobLog { id: 0, repo: repo.path.clone(), log_message: msg.clone(), logged_at: Local::now().to_rfc3339() };
-        log.add_job_log();
-        repo.send_webhook(msg, &repo).await;
-        return;
-    }
-
-    let workflow_start = Instant::now();
-    let mut all_ok = true;
-
-    for (idx, cmd) in ordered.into_iter() {
-        let step_desc = format!("[step {}] {}", idx, cmd.run);
-        info!("Running {} in {}", step_desc, repo.work_dir);
-        println!("Running {} on {}", step_desc, host);
-
-        let t0 = Instant::now();
-        let mut root_iter = cmd.run.split_whitespace();
-        let program = match root_iter.next() {
-            Some(p) => p.to_string(),
-            None => {
-                let msg = format!("Invalid empty command at step {}", idx);
-                error!("{}", msg);
-                let mut log = JobLog { id: 0, repo: repo.path.clone(), log_message: msg.clone(), logged_at: Local::now().to_string() };
-                log.add_job_log();
-                all_ok = false;
-                break;
-            }
-        };
-        let args: Vec<String> = root_iter.map(|s| s.to_string()).collect();
-
-        let output_res = Command::new(&program)
-            .args(&args)
-            .current_dir(&repo.work_dir)
-            .output()
-            .await;
-
-        let dt = t0.elapsed();
-
-        match output_res {
-            Ok(output) => {
-                let success = output.status.success();
-                let code = output.status.code();
-                let stdout_s = String::from_utf8_lossy(&output.stdout).to_string();
-                let stderr_s = String::from_utf8_lossy(&output.stderr).to_string();
-
-                let preview = |s: &str| -> String {
-                    const LIM: usize = 4000; // keep logs reasonable
-                    if s.len() > LIM { format!("{}…", &s[..LIM]) } else { s.to_string() }
-                };
-
-                let msg = if success {
-                    format!(
-                        "✅ {} succeeded in {:.2?} (code {:?})\nstdout:\n{}",
-                        step_desc,
-                        dt,
-                        code,
-                        preview(&stdout_s)
-                    )
-                } else {
-                    format!(
-                        "❌ {} failed in {:.2?} (code {:?})\nstdout:\n{}\nstderr:\n{}",
-                        step_desc,
-                        dt,
-                        code,
-                        preview(&stdout_s),
-                        preview(&stderr_s)
-                    )
-                };
-
-                let mut log = JobLog { id: 0, repo: repo.path.clone(), log_message: msg.clone(), logged_at: Local::now().to_rfc3339() };
-                log.add_job_log();
-                // Best-effort channel message
-                let _ = tx_clone.send(msg.clone()).await;
-                // Send webhook per-step only on failure to reduce noise
-                if !success {
-                    repo.send_webhook(msg.clone(), &repo).await;
-                }
+        if options.notify {
+            repo.send_webhook(msg, &repo).await;
+        }
+    }
 
-                if !success {
-                    all_ok = false;
-                    break;
-                }
-            }
-            Err(e) => {
-                let msg = format!(
-                    "❌ {} failed to start in {:.2?}: {}",
-                    step_desc, dt, e
-                );
-                error!("{}", msg);
-                let mut log = JobLog { id: 0, repo: repo.path.clone(), log_message: msg.clone(), logged_at: Local::now().to_rfc3339() };
-                log.add_job_log();
-                let _ = tx_clone.send(msg.clone()).await;
-                repo.send_webhook(msg.clone(), &repo).await;
-                all_ok = false;
-                break;
-            }
+    let execution = WorkflowExecution {
+        repo: &repo,
+        trigger,
+        db,
+        tx: &tx_clone,
+        host: &host,
+        workflow: Arc::new(workflow),
+        ordered: &ordered,
+        graph: &graph,
+        notify: options.notify && !is_matrix,
+        options: options.clone(),
+    };
+    let mut results = vec![];
+    // Combinations share the checkout, so they run one after another
+    for matrix in combinations {
+        if options.cancel.is_cancelled() {
+            break;
         }
+        let ok = execution.run(&matrix).await;
+        results.push((matrix, ok));
     }
 
-    // Finalize job status and logging
-    let total = workflow_start.elapsed();
-    if all_ok {
+    let all_ok = results.iter().all(|(_, ok)| *ok) && !options.cancel.is_cancelled();
+    let status = match options.cancel.reason() {
+        Some(reason) => reason,
+        None if all_ok => "success".to_string(),
+        None => "failed".to_string(),
+    };
+    // A superseded run leaves the branch's job to the run that replaced it
+    if status != "superseded" {
+        Job::update_status(db, &repo.path, &repo.target_branch, &status);
+        Job::update_finished_time(db, &repo.path, &repo.target_branch);
+    }
+
+    if is_matrix {
+        let passed = results.iter().filter(|(_, ok)| *ok).count();
         let msg = format!(
-            "✅ Workflow completed successfully for {}:{} in {:.2?}",
-            repo.path, repo.target_branch, total
+            "{} Matrix workflow {} for {}:{} ({}/{} combinations passed)\n```\n{}```",
+            if all_ok { "✅" } else { "❌" },
+            if all_ok { "passed" } else { "failed" },
+            repo.path,
+            repo.target_branch,
+            passed,
+            results.len(),
+            matrix_grid(&execution.workflow.matrix, &results)
         );
         info!("{}", msg);
         println!("{}", msg);
-        Job::update_status(repo.path.clone(), repo.target_branch.clone(), "success".to_string());
-        Job::update_finished_time(repo.path.clone(), repo.target_branch.clone());
-        let mut log = JobLog { id: 0, repo: repo.path.clone(), log_message: msg.clone(), logged_at: Local::now().to_rfc3339() };
-        log.add_job_log();
-        repo.send_webhook(msg, &repo).await;
-    } else {
-        let msg = format!(
-            "❌ Workflow failed for {}:{} after {:.2?}",
-            repo.path, repo.target_branch, total
+        let level = if all_ok { "info" } else { "error" };
+        add_log(db, &repo, None, level, &msg);
+        if options.notify {
+            repo.send_webhook(msg, &repo).await;
+        }
+    }
+    all_ok
+}
+
+// Everything needed to run a loaded workflow for one matrix combination
+struct WorkflowExecution<'a> {
+    repo: &'a Repo,
+    trigger: &'a str,
+    db: &'a DbConn,
+    tx: &'a Sender<String>,
+    host: &'a str,
+    workflow: Arc<Workflow>,
+    ordered: &'a BTreeMap<usize, WorkflowCommand>,
+    // Step graph, or why the workflow cannot run
+    graph: &'a Result<StepGraph, String>,
+    options: RunOptions,
+    // Send start/finish webhooks for this run; matrix runs send one summary instead
+    notify: bool,
+}
+
+impl WorkflowExecution<'_> {
+    // Record and execute one run; returns whether it succeeded
+    async fn run(&self, matrix: &BTreeMap<String, String>) -> bool {
+        let (repo, db, ordered) = (self.repo, self.db, self.ordered);
+        let label = matrix_label(matrix);
+        let sha = repo.last_sha.clone().unwrap_or_default();
+        let run_id = Run::start(db, &repo.path, &repo.target_branch, &sha, self.trigger, &label);
+        let suffix = if label.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", label)
+        };
+        let starting_message = format!(
+            "Starting workflow for {} [{}]{} on {}",
+            repo.path, repo.target_branch, suffix, self.host
         );
-        error!("{}", msg);
-        println!("{}", msg);
-        Job::update_status(repo.path.clone(), repo.target_branch.clone(), "failed".to_string());
-        Job::update_finished_time(repo.path.clone(), repo.target_branch.clone());
-        let mut log = JobLog { id: 0, repo: repo.path.clone(), log_message: msg.clone(), logged_at: Local::now().to_rfc3339() };
-        log.add_job_log();
-        repo.send_webhook(msg, &repo).await;
+        info!("{}", starting_message);
+        println!("{}", starting_message);
+        if self.notify {
+            repo.send_webhook(starting_message.clone(), repo).await;
+        }
+
+        let graph = match self.graph {
+            Ok(graph) => graph,
+            Err(msg) => {
+                warn!("{}", msg);
+                println!("{}", msg);
+                add_log(db, repo, run_id, "error", msg);
+                if let Some(id) = run_id {
+                    Run::finish(db, id, "failed", msg, "");
+                }
+                if self.notify {
+                    repo.send_webhook(msg.clone(), repo).await;
+                }
+                return false;
+            }
+        };
+
+        let workflow_start = Instant::now();
+        let mut context = RunContext::new(repo, run_id);
+        context.matrix = matrix.clone();
+        let context = Arc::new(context);
+        context.prepare();
+        let logs = run_id
+            .filter(|_| self.options.log_files)
+            .and_then(RunLogs::for_run)
+            .filter(|logs| match logs.create() {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("unable to create step logs in {}: {}", logs.dir().display(), e);
+                    false
+                }
+            });
+        let runner = Arc::new(StepRunner {
+            db: db.clone(),
+            repo: repo.clone(),
+            run_id,
+            host: self.host.to_string(),
+            tx: self.tx.clone(),
+            notify: self.options.notify,
+            logs: logs.clone(),
+            cancel: self.options.cancel.clone(),
+        });
+        let max_parallel = self.workflow.max_parallel.unwrap_or(DEFAULT_MAX_PARALLEL);
+        let GraphResult {
+            all_ok,
+            error_message,
+            allowed_failures,
+        } = run_graph(
+            runner,
+            graph,
+            ordered,
+            self.workflow.clone(),
+            context.clone(),
+            max_parallel,
+        )
+        .await;
+
+        // Finalize run status and logging
+        context.cleanup();
+        if let Some(logs) = &logs {
+            if let Err(e) = logs.finish() {
+                warn!("unable to compress step logs in {}: {}", logs.dir().display(), e);
+            }
+        }
+        let total = workflow_start.elapsed();
+        if let Some(reason) = self.options.cancel.reason() {
+            let msg = format!(
+                "⏹️ Workflow {} for {}:{}{} after {:.2?}",
+                reason, repo.path, repo.target_branch, suffix, total
+            );
+            warn!("{}", msg);
+            println!("{}", msg);
+            add_log(db, repo, run_id, "warn", &msg);
+            if let Some(id) = run_id {
+                Run::finish(db, id, &reason, &error_message, &msg);
+            }
+            if self.notify {
+                repo.send_webhook(msg, repo).await;
+            }
+            return false;
+        }
+        if all_ok {
+            let allowed = if allowed_failures > 0 {
+                format!(" ({} allowed step failure(s))", allowed_failures)
+            } else {
+                String::new()
+            };
+            let msg = format!(
+                "✅ Workflow completed successfully for {}:{}{} in {:.2?}{}",
+                repo.path, repo.target_branch, suffix, total, allowed
+            );
+            info!("{}", msg);
+            println!("{}", msg);
+            // Log before finishing the run so `logs --follow` sees this line
+            add_log(db, repo, run_id, "info", &msg);
+            if let Some(id) = run_id {
+                Run::finish(db, id, "success", "", &msg);
+            }
+            if self.notify {
+                repo.send_webhook(msg, repo).await;
+            }
+        } else {
+            let msg = format!(
+                "❌ Workflow failed for {}:{}{} after {:.2?}",
+                repo.path, repo.target_branch, suffix, total
+            );
+            error!("{}", msg);
+            println!("{}", msg);
+            add_log(db, repo, run_id, "error", &msg);
+            if let Some(id) = run_id {
+                Run::finish(db, id, "failed", &error_message, &msg);
+            }
+            if self.notify {
+                repo.send_webhook(msg, repo).await;
+            }
+        }
+        all_ok
     }
 }
 
+// Job log row for a run (or for the whole workflow when `run_id` is None)
+fn add_log(db: &DbConn, repo: &Repo, run_id: Option<i64>, level: &str, msg: &str) {
+    let mut log = JobLog {
+        id: 0,
+        repo: repo.path.clone(),
+        log_message: msg.to_string(),
+        logged_at: Local::now().to_rfc3339(),
+        branch: repo.target_branch.clone(),
+        run_id,
+        step_index: None,
+        level: level.to_string(),
+    };
+    log.add_job_log(db);
+}
+
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
 struct WorkflowCommand {
+    // Short title shown instead of the command in logs and notifications
+    name: Option<String>,
+    // Longer explanation shown under the step's result
+    description: Option<String>,
+    // Command line, split into words like a POSIX shell would
+    #[serde(default)]
     run: String,
+    // Multi-line script run from a temp file instead of `run`
+    script: Option<String>,
+    // Interpreter for `run` (as `shell -c run`) or `script`, e.g. "bash"
+    shell: Option<String>,
+    // Extra environment for this step; overrides the workflow-level [env]
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    // Maximum step duration, e.g. "10m"; overrides the workflow-level timeout
+    timeout: Option<String>,
+    // Extra attempts after a failure
+    retries: Option<u32>,
+    // Delay before retrying, e.g. "10s" (default 5s)
+    retry_delay: Option<String>,
+    // "fixed" (default) or "exponential" to double the delay after each attempt
+    retry_backoff: Option<String>,
+    // A failure of this step is logged but does not fail the workflow
+    #[serde(default)]
+    continue_on_error: bool,
+    // Which workflow outcome the step runs for
+    #[serde(default)]
+    when: When,
+    // Steps that must finish first, by index or name; omitted means the previous step, [] means none
+    needs: Option<Vec<StepRef>>,
 }
 
-fn get_command_from_config(path: String) -> HashMap<String, WorkflowCommand> {
-    let empty = HashMap::<String, WorkflowCommand>::new();
-    if let Ok(config_file) = Config::builder()
-        .add_source(config::File::with_name(&path))
-        .build()
-    {
-        if let Ok(map) = config_file.try_deserialize::<HashMap<String, WorkflowCommand>>() {
-            map
-        } else {
-            empty
+// Reference to another step in `needs`, by index or by key
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+enum StepRef {
+    Index(usize),
+    Key(String),
+}
+
+impl fmt::Display for StepRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StepRef::Index(i) => write!(f, "{}", i),
+            StepRef::Key(k) => write!(f, "'{}'", k),
+        }
+    }
+}
+
+impl WorkflowCommand {
+    // Text used to identify the step in logs and run history
+    fn command_text(&self) -> String {
+        match &self.script {
+            Some(script) if self.run.trim().is_empty() => {
+                let first = script.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+                format!("script: {}…", first.trim())
+            }
+            _ => self.run.clone(),
+        }
+    }
+
+    fn has_name(&self) -> bool {
+        self.name.as_deref().is_some_and(|n| !n.trim().is_empty())
+    }
+
+    // The step's name, or its command when unnamed
+    fn title(&self) -> String {
+        match &self.name {
+            Some(name) if self.has_name() => name.trim().to_string(),
+            _ => self.command_text(),
         }
-    } else {
-        empty
+    }
+
+    // "[step N] title", used in every step message
+    fn label(&self, idx: usize) -> String {
+        format!("[step {}] {}", idx, self.title())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum When {
+    #[default]
+    OnSuccess,
+    OnFailure,
+    Always,
+}
+
+impl When {
+    fn as_str(&self) -> &'static str {
+        match self {
+            When::OnSuccess => "on_success",
+            When::OnFailure => "on_failure",
+            When::Always => "always",
+        }
+    }
+
+    fn should_run(&self, all_ok: bool) -> bool {
+        match self {
+            When::OnSuccess => all_ok,
+            When::OnFailure => !all_ok,
+            When::Always => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+struct Workflow {
+    // Environment applied to every step
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    // Default step timeout
+    timeout: Option<String>,
+    // Maximum number of steps running at once
+    max_parallel: Option<usize>,
+    // Variables to expand into one run per combination, e.g. toolchain = ["stable", "beta"]
+    #[serde(default)]
+    matrix: BTreeMap<String, Vec<String>>,
+    // Numeric step tables, e.g. [0], [1]
+    #[serde(flatten)]
+    steps: HashMap<String, WorkflowCommand>,
+}
+
+// Name fragments that mark an environment variable's value as secret in logs
+const SECRET_ENV_MARKERS: [&str; 6] = ["SECRET", "TOKEN", "PASSWORD", "PASSWD", "CREDENTIAL", "KEY"];
+
+fn is_secret_env(name: &str) -> bool {
+    let upper = name.to_uppercase();
+    SECRET_ENV_MARKERS.iter().any(|m| upper.contains(m))
+}
+
+fn merge_env(
+    workflow_env: &BTreeMap<String, String>,
+    step_env: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    let mut env = workflow_env.clone();
+    env.extend(step_env.iter().map(|(k, v)| (k.clone(), v.clone())));
+    env
+}
+
+// One-line summary of a step's environment with secret values masked
+fn describe_env(env: &BTreeMap<String, String>) -> String {
+    if env.is_empty() {
+        return String::new();
+    }
+    let vars = env
+        .iter()
+        .map(|(k, v)| {
+            if is_secret_env(k) {
+                format!("{}=***", k)
+            } else {
+                format!("{}={}", k, v)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ");
+    format!("env: {}", vars)
+}
+
+// Every combination of matrix values; a workflow without [matrix] has one empty combination.
+// Fails when two keys would share a MATRIX_ variable.
+fn matrix_combinations(
+    matrix: &BTreeMap<String, Vec<String>>,
+) -> Result<Vec<BTreeMap<String, String>>, String> {
+    let collisions = matrix_collisions(matrix.keys());
+    if !collisions.is_empty() {
+        return Err(collisions.join("\n  - "));
+    }
+    let mut combinations = vec![BTreeMap::new()];
+    for (key, values) in matrix.iter() {
+        if values.is_empty() {
+            warn!("Ignoring empty matrix variable '{}'", key);
+            continue;
+        }
+        combinations = combinations
+            .into_iter()
+            .flat_map(|combo| {
+                values.iter().map(move |value| {
+                    let mut combo = combo.clone();
+                    combo.insert(key.clone(), value.clone());
+                    combo
+                })
+            })
+            .collect();
+    }
+    Ok(combinations)
+}
+
+// "features=a, toolchain=stable", used in messages and run history
+fn matrix_label(matrix: &BTreeMap<String, String>) -> String {
+    matrix
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// Pass/fail table of matrix results: the last variable forms the columns,
+// the remaining variables form the rows
+fn matrix_grid(
+    matrix: &BTreeMap<String, Vec<String>>,
+    results: &[(BTreeMap<String, String>, bool)],
+) -> String {
+    let Some((col_key, col_values)) = matrix.iter().rfind(|(_, v)| !v.is_empty()) else {
+        return String::new();
+    };
+
+    let mut rows: Vec<String> = vec![];
+    let mut cells: HashMap<(String, String), bool> = HashMap::new();
+    for (combo, ok) in results {
+        let mut others = combo.clone();
+        let col = others.remove(col_key).unwrap_or_default();
+        let row = matrix_label(&others);
+        if !rows.contains(&row) {
+            rows.push(row.clone());
+        }
+        cells.insert((row, col), *ok);
+    }
+
+    let first = rows
+        .iter()
+        .map(|r| r.chars().count())
+        .chain([col_key.chars().count()])
+        .max()
+        .unwrap_or(0);
+    let widths: Vec<usize> = col_values.iter().map(|v| v.chars().count().max(4)).collect();
+
+    let mut out = format!("{:<first$}", col_key);
+    for (value, width) in col_values.iter().zip(&widths) {
+        out.push_str(&format!("  {:<width$}", value));
+    }
+    out.push('\n');
+    for row in rows {
+        out.push_str(&format!("{:<first$}", row));
+        for (value, width) in col_values.iter().zip(&widths) {
+            let cell = match cells.get(&(row.clone(), value.clone())) {
+                Some(true) => "pass",
+                Some(false) => "FAIL",
+                None => "-",
+            };
+            out.push_str(&format!("  {:<width$}", cell));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// Steps by numeric key; `load_workflow` has already rejected any other keys
+fn ordered_steps(workflow: &Workflow) -> BTreeMap<usize, WorkflowCommand> {
+    workflow
+        .steps
+        .iter()
+        .filter_map(|(k, v)| k.trim().parse::<usize>().ok().map(|i| (i, v.clone())))
+        .collect()
+}
+
+// Check a workflow file for `phantom_ci validate`; returns a short summary when valid
+pub fn validate_workflow(file_path: &str) -> Result<String, Vec<String>> {
+    let workflow = load_workflow(file_path)?;
+    let matrix = matrix_combinations(&workflow.matrix)
+        .map_err(|e| vec![e])?
+        .len();
+    let mut summary = format!("{} step(s)", workflow.steps.len());
+    if !workflow.matrix.is_empty() {
+        summary.push_str(&format!(", {} matrix combination(s)", matrix));
+    }
+    Ok(summary)
+}
+
+// Render the step graph of a workflow file for `phantom_ci graph`
+pub fn workflow_graph(file_path: &str) -> Result<String, String> {
+    let workflow = load_workflow(file_path).map_err(|problems| problems.join("\n"))?;
+    let ordered = ordered_steps(&workflow);
+    let graph = StepGraph::build(&ordered)?;
+    Ok(graph.describe(&ordered))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write_workflow(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("phantom_ci-{}.toml", name));
+        fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_load_workflow_env() {
+        let path = write_workflow(
+            "env",
+            "[env]\nREGISTRY = \"registry.example.com\"\n\n[0]\nrun = \"docker build .\"\nenv = { DOCKER_BUILDKIT = \"1\" }\n\n[1]\nrun = \"docker push\"\n",
+        );
+        let workflow = load_workflow(&path).unwrap();
+        assert_eq!(workflow.steps.len(), 2);
+        assert_eq!(workflow.env["REGISTRY"], "registry.example.com");
+        assert_eq!(workflow.steps["0"].env["DOCKER_BUILDKIT"], "1");
+        assert!(workflow.steps["1"].env.is_empty());
+    }
+
+    #[test]
+    fn test_load_workflow_matrix() {
+        let path = write_workflow(
+            "matrix",
+            "[matrix]\ntoolchain = [\"stable\", \"beta\"]\nfeatures = [\"a\", \"b\"]\nversion = [18]\n\n[0]\nrun = \"cargo test\"\n",
+        );
+        let workflow = load_workflow(&path).unwrap();
+        assert_eq!(workflow.steps.len(), 1);
+        assert_eq!(workflow.matrix["toolchain"], vec!["stable", "beta"]);
+        assert_eq!(workflow.matrix["version"], vec!["18"]);
+
+        let combinations = matrix_combinations(&workflow.matrix).unwrap();
+        assert_eq!(combinations.len(), 4);
+        assert_eq!(
+            matrix_label(&combinations[1]),
+            "features=a, toolchain=beta, version=18"
+        );
+        assert_eq!(matrix_combinations(&BTreeMap::new()).unwrap(), vec![BTreeMap::new()]);
+
+        let clash = BTreeMap::from([
+            ("target-os".to_string(), vec!["linux".to_string()]),
+            ("target_os".to_string(), vec!["macos".to_string()]),
+        ]);
+        assert!(matrix_combinations(&clash).unwrap_err().contains("MATRIX_TARGET_OS"));
+    }
+
+    #[test]
+    fn test_step_titles() {
+        let path = write_workflow(
+            "names",
+            "[0]\nname = \"Build\"\ndescription = \"Release binaries\"\nrun = \"bash -lc 'cargo build --release --locked'\"\n\n[1]\nrun = \"make test\"\n\n[2]\nname = \" \"\nrun = \"make deploy\"\n",
+        );
+        let workflow = load_workflow(&path).unwrap();
+        assert_eq!(workflow.steps["0"].label(0), "[step 0] Build");
+        assert_eq!(workflow.steps["0"].description.as_deref(), Some("Release binaries"));
+        assert_eq!(workflow.steps["1"].label(1), "[step 1] make test");
+        assert_eq!(workflow.steps["2"].label(2), "[step 2] make deploy");
+    }
+
+    #[test]
+    fn test_matrix_grid() {
+        let matrix = BTreeMap::from([
+            ("features".to_string(), vec!["a".to_string(), "b".to_string()]),
+            ("toolchain".to_string(), vec!["stable".to_string(), "beta".to_string()]),
+        ]);
+        let results: Vec<(BTreeMap<String, String>, bool)> = matrix_combinations(&matrix)
+            .unwrap()
+            .into_iter()
+            .map(|combo| {
+                let ok = !(combo["features"] == "b" && combo["toolchain"] == "beta");
+                (combo, ok)
+            })
+            .collect();
+        assert_eq!(
+            matrix_grid(&matrix, &results),
+            "toolchain   stable  beta\nfeatures=a  pass    pass\nfeatures=b  pass    FAIL\n"
+        );
+    }
+
+    #[test]
+    fn test_load_workflow_timeouts() {
+        let path = write_workflow(
+            "timeout",
+            "timeout = \"30m\"\n\n[0]\nrun = \"docker push\"\ntimeout = \"10m\"\n\n[1]\nrun = \"true\"\n",
+        );
+        let workflow = load_workflow(&path).unwrap();
+        assert_eq!(workflow.timeout.as_deref(), Some("30m"));
+        assert_eq!(workflow.steps["0"].timeout.as_deref(), Some("10m"));
+        assert_eq!(workflow.steps["1"].timeout, None);
+    }
+
+    #[test]
+    fn test_load_workflow_when() {
+        let path = write_workflow(
+            "when",
+            "[0]\nrun = \"make lint\"\ncontinue_on_error = true\n\n[1]\nrun = \"make\"\n\n[2]\nrun = \"docker logout\"\nwhen = \"always\"\n\n[3]\nrun = \"notify\"\nwhen = \"on_failure\"\n",
+        );
+        let workflow = load_workflow(&path).unwrap();
+        assert!(workflow.steps["0"].continue_on_error);
+        assert_eq!(workflow.steps["1"].when, When::OnSuccess);
+        assert_eq!(workflow.steps["2"].when, When::Always);
+        assert_eq!(workflow.steps["3"].when, When::OnFailure);
+    }
+
+    #[test]
+    fn test_when_should_run() {
+        assert!(When::OnSuccess.should_run(true));
+        assert!(!When::OnSuccess.should_run(false));
+        assert!(!When::OnFailure.should_run(true));
+        assert!(When::OnFailure.should_run(false));
+        assert!(When::Always.should_run(true));
+        assert!(When::Always.should_run(false));
+    }
+
+    #[test]
+    fn test_merge_env_step_overrides_workflow() {
+        let workflow_env = BTreeMap::from([
+            ("A".to_string(), "1".to_string()),
+            ("B".to_string(), "2".to_string()),
+        ]);
+        let step_env = BTreeMap::from([("B".to_string(), "3".to_string())]);
+        let env = merge_env(&workflow_env, &step_env);
+        assert_eq!(env["A"], "1");
+        assert_eq!(env["B"], "3");
+    }
+
+    #[test]
+    fn test_describe_env_masks_secrets() {
+        let env = BTreeMap::from([
+            ("DOCKER_BUILDKIT".to_string(), "1".to_string()),
+            ("REGISTRY_TOKEN".to_string(), "hunter2".to_string()),
+        ]);
+        let desc = describe_env(&env);
+        assert_eq!(desc, "env: DOCKER_BUILDKIT=1 REGISTRY_TOKEN=***");
+        assert_eq!(describe_env(&BTreeMap::new()), "");
     }
 }
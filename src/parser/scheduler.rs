@@ -0,0 +1,514 @@
+use crate::parser::context::RunContext;
+use crate::parser::runner::StepRunner;
+use crate::parser::{StepRef, When, Workflow, WorkflowCommand};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use tokio::task::JoinSet;
+
+// Steps that may run at the same time when a workflow does not set `max_parallel`
+pub const DEFAULT_MAX_PARALLEL: usize = 4;
+
+// Dependency graph of a workflow's steps, keyed by step index.
+// A step without `needs` depends on the step before it, so plain workflows stay sequential.
+#[derive(Debug, Clone)]
+pub struct StepGraph {
+    pub needs: BTreeMap<usize, Vec<usize>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StepState {
+    Succeeded,
+    AllowedFailure,
+    Failed,
+    Skipped,
+}
+
+// Overall result of running a graph
+pub struct GraphResult {
+    pub all_ok: bool,
+    pub error_message: String,
+    pub allowed_failures: usize,
+}
+
+impl StepGraph {
+    pub fn build(steps: &BTreeMap<usize, WorkflowCommand>) -> Result<StepGraph, String> {
+        let mut needs = BTreeMap::new();
+        let mut previous: Option<usize> = None;
+        for (idx, cmd) in steps.iter() {
+            let deps = match &cmd.needs {
+                None => previous.into_iter().collect::<Vec<usize>>(),
+                Some(refs) => {
+                    let mut deps = BTreeSet::new();
+                    for r in refs {
+                        let dep =
+                            resolve(r, steps).map_err(|e| format!("step {} needs {}", idx, e))?;
+                        if dep == *idx {
+                            return Err(format!("step {} needs itself", idx));
+                        }
+                        deps.insert(dep);
+                    }
+                    deps.into_iter().collect()
+                }
+            };
+            needs.insert(*idx, deps);
+            previous = Some(*idx);
+        }
+
+        let graph = StepGraph { needs };
+        graph.check_cycles()?;
+        Ok(graph)
+    }
+
+    // Kahn's algorithm; whatever cannot be ordered is part of a cycle
+    fn check_cycles(&self) -> Result<(), String> {
+        let ordered: usize = self.stages().iter().map(|s| s.len()).sum();
+        if ordered == self.needs.len() {
+            return Ok(());
+        }
+        let placed: BTreeSet<usize> = self.stages().into_iter().flatten().collect();
+        let cycle = self
+            .needs
+            .keys()
+            .filter(|idx| !placed.contains(idx))
+            .map(|idx| idx.to_string())
+            .collect::<Vec<String>>();
+        Err(format!(
+            "dependency cycle between steps {}",
+            cycle.join(", ")
+        ))
+    }
+
+    // Group steps by depth: every step's needs are in earlier stages
+    pub fn stages(&self) -> Vec<Vec<usize>> {
+        let mut placed = BTreeSet::new();
+        let mut stages = vec![];
+        loop {
+            let stage: Vec<usize> = self
+                .needs
+                .iter()
+                .filter(|(idx, deps)| {
+                    !placed.contains(*idx) && deps.iter().all(|d| placed.contains(d))
+                })
+                .map(|(idx, _)| *idx)
+                .collect();
+            if stage.is_empty() {
+                break;
+            }
+            placed.extend(stage.iter().copied());
+            stages.push(stage);
+        }
+        stages
+    }
+
+    // Render the graph for `phantom_ci graph`
+    pub fn describe(&self, steps: &BTreeMap<usize, WorkflowCommand>) -> String {
+        let mut out = String::new();
+        for (depth, stage) in self.stages().iter().enumerate() {
+            for (i, idx) in stage.iter().enumerate() {
+                let label = if i == 0 {
+                    format!("stage {}:", depth)
+                } else {
+                    String::new()
+                };
+                let needs = &self.needs[idx];
+                let needs = if needs.is_empty() {
+                    String::new()
+                } else {
+                    format!(
+                        "  <- needs {}",
+                        needs
+                            .iter()
+                            .map(|d| d.to_string())
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    )
+                };
+                let text = steps.get(idx).map(|c| c.title()).unwrap_or_default();
+                out.push_str(&format!("{:<9} [{}] {}{}\n", label, idx, text, needs));
+            }
+        }
+        out
+    }
+}
+
+// Find the step a `needs` entry refers to: an index, a numeric key or a step name
+fn resolve(r: &StepRef, steps: &BTreeMap<usize, WorkflowCommand>) -> Result<usize, String> {
+    let unknown = || format!("unknown step {}", r);
+    let key = match r {
+        StepRef::Index(i) => return steps.contains_key(i).then_some(*i).ok_or_else(unknown),
+        StepRef::Key(key) => key.trim(),
+    };
+    if let Ok(i) = key.parse::<usize>() {
+        return steps.contains_key(&i).then_some(i).ok_or_else(unknown);
+    }
+
+    let named: Vec<usize> = steps
+        .iter()
+        .filter(|(_, cmd)| cmd.has_name() && cmd.title() == key)
+        .map(|(idx, _)| *idx)
+        .collect();
+    match named.as_slice() {
+        [idx] => Ok(*idx),
+        [] => Err(unknown()),
+        _ => Err(format!(
+            "{}, which names steps {}",
+            r,
+            named
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        )),
+    }
+}
+
+// Run every step once its needs have finished, at most `max_parallel` at a time.
+// After a failure the `on_success` steps downstream of it are cancelled; unrelated steps keep
+// going. `on_failure`/`always` steps wait until nothing else is running or ready, so the outcome
+// they see does not depend on which step happens to finish first.
+// Once the run is cancelled no step is started at all.
+pub async fn run_graph(
+    runner: Arc<StepRunner>,
+    graph: &StepGraph,
+    steps: &BTreeMap<usize, WorkflowCommand>,
+    workflow: Arc<Workflow>,
+    context: Arc<RunContext>,
+    max_parallel: usize,
+) -> GraphResult {
+    let mut states: BTreeMap<usize, StepState> = BTreeMap::new();
+    let mut started: BTreeSet<usize> = BTreeSet::new();
+    // Failed steps and every step downstream of one
+    let mut after_failure: BTreeSet<usize> = BTreeSet::new();
+    let mut running = JoinSet::new();
+    let mut tasks: BTreeMap<tokio::task::Id, usize> = BTreeMap::new();
+    let mut result = GraphResult {
+        all_ok: true,
+        error_message: String::new(),
+        allowed_failures: 0,
+    };
+
+    loop {
+        // Start (or skip) every step whose needs are done, in index order
+        let mut progressed = true;
+        while progressed {
+            progressed = false;
+            let ready: Vec<usize> = graph
+                .needs
+                .iter()
+                .filter(|(idx, deps)| {
+                    !started.contains(*idx) && deps.iter().all(|d| states.contains_key(d))
+                })
+                .map(|(idx, _)| *idx)
+                .collect();
+            let settled =
+                running.is_empty() && !ready.iter().any(|idx| steps[idx].when == When::OnSuccess);
+            for idx in ready {
+                let cmd = &steps[&idx];
+                if let Some(reason) = runner.cancel.reason() {
+                    runner.skip_cancelled(idx, cmd, &reason);
+                    started.insert(idx);
+                    states.insert(idx, StepState::Skipped);
+                    progressed = true;
+                    if result.error_message.is_empty() {
+                        result.error_message = format!("run {}", reason);
+                    }
+                    result.all_ok = false;
+                    continue;
+                }
+                let upstream_failed = graph.needs[&idx].iter().any(|d| after_failure.contains(d));
+                let skip = match cmd.when {
+                    When::OnSuccess => upstream_failed,
+                    _ if !settled => continue,
+                    _ => !cmd.when.should_run(result.all_ok),
+                };
+                if skip {
+                    runner.skip(idx, cmd, cmd.when == When::OnSuccess);
+                    started.insert(idx);
+                    states.insert(idx, StepState::Skipped);
+                    if upstream_failed {
+                        after_failure.insert(idx);
+                    }
+                    progressed = true;
+                    continue;
+                }
+                if running.len() >= max_parallel.max(1) {
+                    continue;
+                }
+                started.insert(idx);
+                if upstream_failed {
+                    after_failure.insert(idx);
+                }
+                progressed = true;
+
+                let cmd = cmd.clone();
+                let runner = runner.clone();
+                let workflow = workflow.clone();
+                let context = context.clone();
+                let task = running.spawn(async move {
+                    let res = runner.run_step(idx, &cmd, &workflow, &context).await;
+                    (idx, res)
+                });
+                tasks.insert(task.id(), idx);
+            }
+        }
+
+        let Some(joined) = running.join_next().await else {
+            break;
+        };
+        let (idx, res) = match joined {
+            Ok(done) => done,
+            // A panicking step task counts as a failure of that step
+            Err(e) => {
+                let idx = tasks[&e.id()];
+                (idx, Err(format!("{} task failed: {}", steps[&idx].label(idx), e)))
+            }
+        };
+        let state = match res {
+            Ok(()) => StepState::Succeeded,
+            Err(_) if steps[&idx].continue_on_error => {
+                result.allowed_failures += 1;
+                StepState::AllowedFailure
+            }
+            Err(e) => {
+                // Keep the first failure; later ones are usually cleanup fallout
+                if result.all_ok {
+                    result.error_message = e;
+                }
+                result.all_ok = false;
+                after_failure.insert(idx);
+                StepState::Failed
+            }
+        };
+        states.insert(idx, state);
+    }
+
+    if states.len() < graph.needs.len() && result.error_message.is_empty() {
+        result.all_ok = false;
+        result.error_message = "workflow stopped before all steps finished".to_string();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::run::{Run, RunStep};
+    use crate::database::SqliteConnection;
+    use crate::parser::cancel::CancelToken;
+    use crate::repo::Repo;
+
+    fn step(run: &str, needs: Option<Vec<StepRef>>) -> WorkflowCommand {
+        let mut cmd: WorkflowCommand =
+            serde_json::from_value(serde_json::json!({ "run": run })).unwrap();
+        cmd.needs = needs;
+        cmd
+    }
+
+    #[test]
+    fn test_default_graph_is_sequential() {
+        let steps = BTreeMap::from([
+            (0, step("a", None)),
+            (1, step("b", None)),
+            (5, step("c", None)),
+        ]);
+        let graph = StepGraph::build(&steps).unwrap();
+        assert_eq!(graph.stages(), vec![vec![0], vec![1], vec![5]]);
+    }
+
+    #[test]
+    fn test_needs_graph_stages() {
+        let steps = BTreeMap::from([
+            (0, step("fmt", Some(vec![]))),
+            (1, step("build", Some(vec![StepRef::Index(0)]))),
+            (2, step("clippy", Some(vec![StepRef::Key("0".into())]))),
+            (
+                3,
+                step("deploy", Some(vec![StepRef::Index(1), StepRef::Index(2)])),
+            ),
+            (4, step("lint", Some(vec![]))),
+        ]);
+        let graph = StepGraph::build(&steps).unwrap();
+        assert_eq!(graph.stages(), vec![vec![0, 4], vec![1, 2], vec![3]]);
+        let text = graph.describe(&steps);
+        assert!(text.contains("[3] deploy  <- needs 1, 2"));
+    }
+
+    #[test]
+    fn test_needs_by_name() {
+        let mut build = step("cargo build --release", Some(vec![]));
+        build.name = Some("Build".into());
+        let mut lint = step("cargo clippy", Some(vec![]));
+        lint.name = Some("Lint".into());
+        let steps = BTreeMap::from([
+            (0, build),
+            (1, lint),
+            (
+                2,
+                step(
+                    "make deploy",
+                    Some(vec![
+                        StepRef::Key("Build".into()),
+                        StepRef::Key("Lint".into()),
+                    ]),
+                ),
+            ),
+        ]);
+        let graph = StepGraph::build(&steps).unwrap();
+        assert_eq!(graph.needs[&2], vec![0, 1]);
+        assert!(graph.describe(&steps).contains("[0] Build"));
+
+        let mut dup = steps.clone();
+        dup.get_mut(&1).unwrap().name = Some("Build".into());
+        assert_eq!(
+            StepGraph::build(&dup).unwrap_err(),
+            "step 2 needs 'Build', which names steps 0, 1"
+        );
+    }
+
+    #[test]
+    fn test_graph_errors() {
+        let unknown = BTreeMap::from([(0, step("a", Some(vec![StepRef::Index(9)])))]);
+        assert!(StepGraph::build(&unknown)
+            .unwrap_err()
+            .contains("unknown step 9"));
+
+        let itself = BTreeMap::from([(0, step("a", Some(vec![StepRef::Index(0)])))]);
+        assert!(StepGraph::build(&itself)
+            .unwrap_err()
+            .contains("needs itself"));
+
+        let cycle = BTreeMap::from([
+            (0, step("a", Some(vec![StepRef::Index(1)]))),
+            (1, step("b", Some(vec![StepRef::Index(0)]))),
+            (2, step("c", Some(vec![]))),
+        ]);
+        assert_eq!(
+            StepGraph::build(&cycle).unwrap_err(),
+            "dependency cycle between steps 0, 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_graph_cancels_dependents() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let work_dir = std::env::temp_dir().join("phantom_ci-test-graph");
+        std::fs::create_dir_all(&work_dir).unwrap();
+        let repo = Repo {
+            path: "test/graph".into(),
+            work_dir: work_dir.to_string_lossy().to_string(),
+            target_branch: "main".into(),
+            ..Default::default()
+        };
+        let run_id = Run::start(&db, &repo.path, "main", "abc", "test", "");
+        let (tx, _rx) = tokio::sync::mpsc::channel(64);
+        let runner = Arc::new(StepRunner {
+            db: db.clone(),
+            repo: repo.clone(),
+            run_id,
+            host: "test".into(),
+            tx,
+            notify: false,
+            logs: None,
+            cancel: CancelToken::default(),
+        });
+
+        let mut always = step("true", Some(vec![StepRef::Index(1)]));
+        always.when = When::Always;
+        // Ready from the start, but only decided once the failure below has happened
+        let mut on_failure = step("true", Some(vec![]));
+        on_failure.when = When::OnFailure;
+        let steps = BTreeMap::from([
+            (0, step("sleep 0.4", Some(vec![]))),
+            (1, step("sh -c 'sleep 0.2; exit 1'", Some(vec![]))),
+            (2, step("true", Some(vec![StepRef::Index(1)]))),
+            (3, always),
+            (4, step("true", Some(vec![StepRef::Index(0)]))),
+            (5, on_failure),
+            (6, step("true", Some(vec![StepRef::Index(3)]))),
+        ]);
+        let graph = StepGraph::build(&steps).unwrap();
+        let context = Arc::new(RunContext::new(&repo, run_id));
+        context.prepare();
+        let result = run_graph(
+            runner,
+            &graph,
+            &steps,
+            Arc::new(Workflow::default()),
+            context.clone(),
+            2,
+        )
+        .await;
+        context.cleanup();
+
+        assert!(!result.all_ok);
+        let statuses: BTreeMap<i64, String> = RunStep::get_steps(&db, run_id.unwrap())
+            .into_iter()
+            .map(|s| (s.step_index, s.status))
+            .collect();
+        assert_eq!(statuses[&0], "success");
+        assert_eq!(statuses[&1], "failed");
+        assert_eq!(statuses[&2], "cancelled");
+        assert_eq!(statuses[&3], "success");
+        // Started after the failure, but not downstream of it
+        assert_eq!(statuses[&4], "success");
+        assert_eq!(statuses[&5], "success");
+        // Downstream of it through the `always` step
+        assert_eq!(statuses[&6], "cancelled");
+    }
+
+    #[tokio::test]
+    async fn test_run_graph_stops_when_cancelled() {
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let work_dir = std::env::temp_dir().join("phantom_ci-test-graph-cancel");
+        std::fs::create_dir_all(&work_dir).unwrap();
+        let repo = Repo {
+            path: "test/graph".into(),
+            work_dir: work_dir.to_string_lossy().to_string(),
+            target_branch: "main".into(),
+            ..Default::default()
+        };
+        let run_id = Run::start(&db, &repo.path, "main", "abc", "test", "");
+        let (tx, _rx) = tokio::sync::mpsc::channel(64);
+        let cancel = CancelToken::default();
+        let runner = Arc::new(StepRunner {
+            db: db.clone(),
+            repo: repo.clone(),
+            run_id,
+            host: "test".into(),
+            tx,
+            notify: false,
+            logs: None,
+            cancel: cancel.clone(),
+        });
+
+        let mut always = step("true", None);
+        always.when = When::Always;
+        let steps = BTreeMap::from([(0, step("sleep 30", None)), (1, always)]);
+        let graph = StepGraph::build(&steps).unwrap();
+        let context = Arc::new(RunContext::new(&repo, run_id));
+        context.prepare();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            cancel.cancel("superseded");
+        });
+        let result = run_graph(
+            runner,
+            &graph,
+            &steps,
+            Arc::new(Workflow::default()),
+            context.clone(),
+            2,
+        )
+        .await;
+        context.cleanup();
+
+        // Not even `always` steps start once the run is cancelled
+        assert!(!result.all_ok);
+        assert!(result.error_message.contains("cancelled (superseded)"));
+        let statuses: Vec<String> = RunStep::get_steps(&db, run_id.unwrap())
+            .into_iter()
+            .map(|s| s.status)
+            .collect();
+        assert_eq!(statuses, vec!["cancelled", "cancelled"]);
+    }
+}
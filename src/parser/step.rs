@@ -0,0 +1,363 @@
+use crate::parser::cancel::CancelToken;
+use chrono::{DateTime, Local};
+use std::collections::BTreeMap;
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+
+// How long a timed-out step gets between SIGTERM and SIGKILL
+const KILL_GRACE: Duration = Duration::from_secs(10);
+// How long to wait for output pipes after the process group is gone
+const PIPE_DRAIN: Duration = Duration::from_secs(5);
+// Bytes of each stream kept in the outcome for step messages
+const OUTPUT_TAIL: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepStatus {
+    Success,
+    Failed,
+    TimedOut(Duration),
+    StartFailed(String),
+    // Stopped through the run's CancelToken, with its reason
+    Cancelled(String),
+}
+
+// Which pipe a line of output came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+// One line of step output, stamped when it was read
+#[derive(Debug, Clone)]
+pub struct OutputLine {
+    pub stream: Stream,
+    pub text: String,
+    pub at: DateTime<Local>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StepOutcome {
+    pub status: StepStatus,
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub duration: Duration,
+}
+
+impl StepOutcome {
+    pub fn success(&self) -> bool {
+        self.status == StepStatus::Success
+    }
+
+    // Value stored in run_steps.status
+    pub fn status_str(&self) -> &'static str {
+        match self.status {
+            StepStatus::Success => "success",
+            StepStatus::Failed => "failed",
+            StepStatus::TimedOut(_) => "timed_out",
+            StepStatus::StartFailed(_) => "error",
+            StepStatus::Cancelled(_) => "cancelled",
+        }
+    }
+}
+
+// Run one step in its own process group so a timeout or cancellation can stop everything it
+// spawned.
+// Output is read line by line and handed to `lines` as it arrives; the outcome keeps
+// only the tail of each stream.
+pub async fn execute(
+    program: &str,
+    args: &[String],
+    env: &BTreeMap<String, String>,
+    work_dir: &str,
+    timeout: Option<Duration>,
+    lines: Option<Sender<OutputLine>>,
+    cancel: Option<&CancelToken>,
+) -> StepOutcome {
+    let t0 = Instant::now();
+    let spawned = Command::new(program)
+        .args(args)
+        .envs(env)
+        .current_dir(work_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn();
+
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            return StepOutcome {
+                status: StepStatus::StartFailed(e.to_string()),
+                code: None,
+                stdout: String::new(),
+                stderr: String::new(),
+                duration: t0.elapsed(),
+            }
+        }
+    };
+
+    let stdout_task = read_pipe(child.stdout.take(), Stream::Stdout, lines.clone());
+    let stderr_task = read_pipe(child.stderr.take(), Stream::Stderr, lines);
+
+    let stopped = tokio::select! {
+        status = wait_within(&mut child, timeout) => match status {
+            Some(status) => Ok(status.ok()),
+            None => Err(StepStatus::TimedOut(timeout.unwrap_or_default())),
+        },
+        reason = cancelled(cancel) => Err(StepStatus::Cancelled(reason)),
+    };
+    let (status, stopped) = match stopped {
+        Ok(status) => (status, None),
+        Err(stop) => (terminate_group(&mut child).await, Some(stop)),
+    };
+
+    let (stdout, stderr) = if stopped.is_some() {
+        (
+            join_pipe_within(stdout_task, PIPE_DRAIN).await,
+            join_pipe_within(stderr_task, PIPE_DRAIN).await,
+        )
+    } else {
+        (
+            stdout_task.await.unwrap_or_default(),
+            stderr_task.await.unwrap_or_default(),
+        )
+    };
+
+    let code = status.and_then(|s| s.code());
+    let status = match (stopped, status) {
+        (Some(stop), _) => stop,
+        (None, Some(s)) if s.success() => StepStatus::Success,
+        (None, _) => StepStatus::Failed,
+    };
+
+    StepOutcome {
+        status,
+        code,
+        stdout,
+        stderr,
+        duration: t0.elapsed(),
+    }
+}
+
+// Wait for the step to exit; None once `limit` has passed
+async fn wait_within(
+    child: &mut Child,
+    limit: Option<Duration>,
+) -> Option<std::io::Result<ExitStatus>> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, child.wait()).await.ok(),
+        None => Some(child.wait().await),
+    }
+}
+
+async fn cancelled(cancel: Option<&CancelToken>) -> String {
+    match cancel {
+        Some(cancel) => cancel.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn read_pipe<R>(pipe: Option<R>, stream: Stream, lines: Option<Sender<OutputLine>>) -> JoinHandle<String>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut tail = String::new();
+        let Some(pipe) = pipe else {
+            return tail;
+        };
+        let mut reader = BufReader::new(pipe);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let at = Local::now();
+            let raw = String::from_utf8_lossy(&buf);
+            tail.push_str(&raw);
+            keep_tail(&mut tail, OUTPUT_TAIL);
+            if let Some(lines) = &lines {
+                let text = raw.trim_end_matches(['\r', '\n']).to_string();
+                // The receiver going away must not stop us draining the pipe
+                let _ = lines.send(OutputLine { stream, text, at }).await;
+            }
+        }
+        tail
+    })
+}
+
+// Drop the start of `buf` once it grows past twice `limit`, keeping the last `limit` bytes
+fn keep_tail(buf: &mut String, limit: usize) {
+    if buf.len() <= limit * 2 {
+        return;
+    }
+    let mut cut = buf.len() - limit;
+    while !buf.is_char_boundary(cut) {
+        cut += 1;
+    }
+    buf.drain(..cut);
+}
+
+// A background process that escaped the group can keep a pipe open; don't wait on it forever.
+// Aborting the reader also releases its handle on the output channel.
+async fn join_pipe_within(mut task: JoinHandle<String>, limit: Duration) -> String {
+    match tokio::time::timeout(limit, &mut task).await {
+        Ok(Ok(s)) => s,
+        _ => {
+            task.abort();
+            String::new()
+        }
+    }
+}
+
+// SIGTERM the step's process group, then SIGKILL whatever is left after the grace period
+pub async fn terminate_group(child: &mut Child) -> Option<ExitStatus> {
+    let Some(pid) = child.id() else {
+        return child.wait().await.ok();
+    };
+    signal_group(pid, libc::SIGTERM);
+    if let Ok(status) = tokio::time::timeout(KILL_GRACE, child.wait()).await {
+        // The leader exited; make sure nothing else in the group survives it
+        signal_group(pid, libc::SIGKILL);
+        return status.ok();
+    }
+    signal_group(pid, libc::SIGKILL);
+    child.wait().await.ok()
+}
+
+fn signal_group(pid: u32, signal: libc::c_int) {
+    // The step was spawned with process_group(0), so its pgid equals its pid
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), signal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_execute_success() {
+        let outcome = execute(
+            "echo",
+            &["hello".into()],
+            &BTreeMap::new(),
+            "/",
+            None,
+            None,
+            None,
+        )
+        .await;
+        assert!(outcome.success());
+        assert_eq!(outcome.code, Some(0));
+        assert_eq!(outcome.stdout.trim(), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_execute_streams_lines() {
+        let (sender, mut lines) = tokio::sync::mpsc::channel(16);
+        let args = vec![
+            "-c".to_string(),
+            "echo one; sleep 0.1; echo two >&2; sleep 0.1; printf three".to_string(),
+        ];
+        let outcome = execute(
+            "sh",
+            &args,
+            &BTreeMap::new(),
+            "/",
+            None,
+            Some(sender),
+            None,
+        )
+        .await;
+        assert!(outcome.success());
+        assert_eq!(outcome.stdout, "one\nthree");
+        assert_eq!(outcome.stderr, "two\n");
+
+        let mut got = vec![];
+        while let Some(line) = lines.recv().await {
+            got.push((line.stream, line.text));
+        }
+        assert_eq!(
+            got,
+            vec![
+                (Stream::Stdout, "one".to_string()),
+                (Stream::Stderr, "two".to_string()),
+                (Stream::Stdout, "three".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep_tail() {
+        let mut buf = "é".repeat(10);
+        keep_tail(&mut buf, 4);
+        assert_eq!(buf, "éé");
+        let mut short = "abc".to_string();
+        keep_tail(&mut short, 4);
+        assert_eq!(short, "abc");
+    }
+
+    #[tokio::test]
+    async fn test_execute_start_failure() {
+        let outcome =
+            execute("phantom_ci-no-such-binary", &[], &BTreeMap::new(), "/", None, None, None).await;
+        assert!(matches!(outcome.status, StepStatus::StartFailed(_)));
+        assert_eq!(outcome.status_str(), "error");
+    }
+
+    #[tokio::test]
+    async fn test_execute_timeout_kills_group() {
+        let args = vec!["-c".to_string(), "sleep 30 & sleep 30; echo done".to_string()];
+        let outcome = execute(
+            "sh",
+            &args,
+            &BTreeMap::new(),
+            "/",
+            Some(Duration::from_millis(200)),
+            None,
+            None,
+        )
+        .await;
+        assert_eq!(outcome.status, StepStatus::TimedOut(Duration::from_millis(200)));
+        assert_eq!(outcome.status_str(), "timed_out");
+        assert!(outcome.duration < Duration::from_secs(10));
+        assert!(!outcome.stdout.contains("done"));
+    }
+
+    #[tokio::test]
+    async fn test_execute_cancel_kills_group() {
+        let cancel = CancelToken::default();
+        let args = vec!["-c".to_string(), "sleep 30 & sleep 30; echo done".to_string()];
+        let stop = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            stop.cancel("superseded");
+        });
+        let outcome = execute(
+            "sh",
+            &args,
+            &BTreeMap::new(),
+            "/",
+            Some(Duration::from_secs(30)),
+            None,
+            Some(&cancel),
+        )
+        .await;
+        assert_eq!(
+            outcome.status,
+            StepStatus::Cancelled("superseded".to_string())
+        );
+        assert_eq!(outcome.status_str(), "cancelled");
+        assert!(outcome.duration < Duration::from_secs(10));
+    }
+}
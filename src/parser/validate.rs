@@ -0,0 +1,194 @@
+use crate::parser::context::matrix_collisions;
+use crate::parser::runner::step_settings;
+use crate::parser::scheduler::StepGraph;
+use crate::parser::{Workflow, WorkflowCommand};
+use crate::util::parse_duration;
+use config::{Config, File, FileFormat, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+// Top-level keys that configure the workflow rather than define a step
+const WORKFLOW_KEYS: [&str; 4] = ["env", "timeout", "max_parallel", "matrix"];
+
+// Load a workflow file, collecting every problem that would stop or break a run.
+// Used by `phantom_ci validate` and before each run.
+pub fn load_workflow(file_path: &str) -> Result<Workflow, Vec<String>> {
+    let content = fs::read_to_string(file_path)
+        .map_err(|e| vec![format!("unable to read {}: {}", file_path, e)])?;
+    parse_workflow_toml(&content)
+}
+
+fn parse_workflow_toml(content: &str) -> Result<Workflow, Vec<String>> {
+    // Syntax errors carry the line and column
+    let config = Config::builder()
+        .add_source(File::from_str(content, FileFormat::Toml))
+        .build()
+        .map_err(|e| vec![e.to_string().trim_end().to_string()])?;
+    let table = config
+        .clone()
+        .try_deserialize::<HashMap<String, Value>>()
+        .map_err(|e| vec![e.to_string()])?;
+
+    let mut problems = vec![];
+    let mut keys: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    let mut steps: BTreeMap<usize, WorkflowCommand> = BTreeMap::new();
+    let mut names: Vec<&String> = table.keys().collect();
+    names.sort();
+    for key in names {
+        if WORKFLOW_KEYS.contains(&key.as_str()) {
+            continue;
+        }
+        let value = table[key].clone();
+        if value.clone().into_table().is_err() {
+            problems.push(format!("unknown key '{}'", key));
+            continue;
+        }
+        let Ok(idx) = key.trim().parse::<usize>() else {
+            problems.push(format!("step key [{}] is not a number", key));
+            continue;
+        };
+        keys.entry(idx).or_default().push(key.clone());
+        match value.try_deserialize::<WorkflowCommand>() {
+            Ok(cmd) => {
+                steps.insert(idx, cmd);
+            }
+            Err(e) => problems.push(format!("step [{}]: {}", key, e)),
+        }
+    }
+    for (idx, keys) in keys.iter().filter(|(_, keys)| keys.len() > 1) {
+        let keys = keys
+            .iter()
+            .map(|k| format!("[{}]", k))
+            .collect::<Vec<String>>();
+        problems.push(format!("steps {} all have index {}", keys.join(", "), idx));
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    let mut workflow = config
+        .try_deserialize::<Workflow>()
+        .map_err(|e| vec![e.to_string()])?;
+    if let Some(timeout) = &workflow.timeout {
+        if let Err(e) = parse_duration(timeout) {
+            problems.push(format!("invalid timeout: {}", e));
+            workflow.timeout = None;
+        }
+    }
+    problems.extend(matrix_collisions(workflow.matrix.keys()));
+    for (idx, cmd) in steps.iter() {
+        problems.extend(
+            check_step(cmd, &workflow)
+                .into_iter()
+                .map(|p| format!("step {}: {}", idx, p)),
+        );
+    }
+    if steps.is_empty() {
+        problems.push("no steps defined".to_string());
+    } else if let Err(e) = StepGraph::build(&steps) {
+        problems.push(e);
+    }
+
+    if problems.is_empty() {
+        Ok(workflow)
+    } else {
+        Err(problems)
+    }
+}
+
+// Problems with a single step that are only found when it runs
+fn check_step(cmd: &WorkflowCommand, workflow: &Workflow) -> Vec<String> {
+    let mut problems = vec![];
+    match &cmd.script {
+        Some(_) if !cmd.run.trim().is_empty() => {
+            problems.push("set either `run` or `script`, not both".to_string())
+        }
+        Some(script) if script.trim().is_empty() => problems.push("`script` is empty".to_string()),
+        Some(_) => {}
+        None if cmd.run.trim().is_empty() => problems.push("`run` is empty".to_string()),
+        None if cmd.shell.is_none() && shlex::split(&cmd.run).is_none() => {
+            problems.push(format!("unbalanced quotes in `run`: {}", cmd.run))
+        }
+        None => {}
+    }
+    if let Some(shell) = &cmd.shell {
+        if shlex::split(shell).is_none_or(|argv| argv.is_empty()) {
+            problems.push(format!("invalid shell '{}'", shell));
+        }
+    }
+    if let Err(e) = step_settings(cmd, workflow) {
+        problems.push(e);
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_workflow() {
+        let workflow = parse_workflow_toml(
+            "timeout = \"30m\"\n\n[0]\nname = \"Build\"\nrun = \"make\"\n\n[1]\nscript = \"make test\"\nneeds = [\"Build\"]\n",
+        )
+        .unwrap();
+        assert_eq!(workflow.steps.len(), 2);
+    }
+
+    #[test]
+    fn test_syntax_error_has_line() {
+        let problems =
+            parse_workflow_toml("[0]\nrun = \"make\"\n\n[1]\nrun = make test\n").unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("line 5"), "{}", problems[0]);
+    }
+
+    #[test]
+    fn test_structure_problems() {
+        let problems = parse_workflow_toml(
+            "foo = 1\n\n[build]\nrun = \"make\"\n\n[1]\nrn = \"make\"\n\n[2]\nrun = \"a\"\n\n[02]\nrun = \"b\"\n",
+        )
+        .unwrap_err();
+        assert!(
+            problems[0].starts_with("step [1]: unknown field `rn`"),
+            "{}",
+            problems[0]
+        );
+        assert_eq!(problems[1], "step key [build] is not a number");
+        assert_eq!(problems[2], "unknown key 'foo'");
+        assert_eq!(problems[3], "steps [02], [2] all have index 2");
+    }
+
+    #[test]
+    fn test_step_problems() {
+        let problems = parse_workflow_toml(
+            "timeout = \"soon\"\n\n[0]\nrun = \"\"\n\n[1]\nrun = \"echo 'oops\"\n\n[2]\nrun = \"make\"\nretry_backoff = \"linear\"\n\n[3]\nrun = \"make\"\nneeds = [7]\n\n[4]\nrun = \"make\"\nretries = 4294967295\n",
+        )
+        .unwrap_err();
+        assert!(problems[0].starts_with("invalid timeout"));
+        assert_eq!(problems[1], "step 0: `run` is empty");
+        assert!(problems[2].starts_with("step 1: unbalanced quotes"));
+        assert!(problems[3].starts_with("step 2: invalid retry_backoff 'linear'"));
+        assert_eq!(
+            problems[4],
+            "step 4: invalid retries 4294967295 (at most 100)"
+        );
+        assert_eq!(problems[5], "step 3 needs unknown step 7");
+        assert_eq!(
+            parse_workflow_toml("timeout = \"1m\"\n").unwrap_err(),
+            vec!["no steps defined"]
+        );
+    }
+
+    #[test]
+    fn test_matrix_key_collision() {
+        let problems = parse_workflow_toml(
+            "[matrix]\ntarget-os = [\"linux\"]\ntarget_os = [\"macos\"]\n\n[0]\nrun = \"make\"\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            problems,
+            vec!["matrix keys 'target-os' and 'target_os' are both MATRIX_TARGET_OS"]
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use crate::database::job::Job;
-use crate::parser::parse_workflow;
-use crate::util::{default_config_path, default_repo_work_path};
+use crate::database::DbConn;
+use crate::database::queue::QueueEntry;
+use crate::parser::{parse_workflow, CancelToken, RunOptions};
+use crate::util::{default_config_path, parse_duration};
 use crate::webhook::{Webhook, WebhookConfig, WebhookType};
 use chrono::Local;
 use config::Config;
@@ -10,9 +12,13 @@ use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::Path;
 use std::process::{exit, Command};
+use std::time::Duration;
 use std::{env, fs};
 use tokio::sync::mpsc::Sender;
 
+// Longest quiet_period honoured; a longer one is reported and ignored like an invalid value
+const MAX_QUIET_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
 // Lightweight Git client abstraction for easier testing
 trait GitClient {
     fn has_remote_branch(&self, work_dir: &str, branch: &str) -> bool;
@@ -95,9 +101,23 @@ pub struct Repo {
     pub work_dir: String,
     pub last_sha: Option<String>,
     pub target_branch: String,
-    pub triggered_branches: Vec<String>,
     pub ssh_key_path: Option<String>,
     pub branch_exclusions: Option<String>,
+    // SHA each triggered branch was at before the change, keyed by branch
+    #[serde(default)]
+    pub previous_shas: HashMap<String, String>,
+    // Jobs of this repo that may run at once (default 1)
+    #[serde(default)]
+    pub max_concurrent_jobs: Option<usize>,
+    // Stop a branch's running build when a newer commit is queued for it
+    #[serde(default)]
+    pub cancel_superseded: bool,
+    // Comma-separated branches to do that for when it is not set for all of them
+    #[serde(default)]
+    pub cancel_superseded_branches: Option<String>,
+    // Build only once the branch has not moved for this long, e.g. "2m"
+    #[serde(default)]
+    pub quiet_period: Option<String>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
@@ -106,6 +126,18 @@ pub struct Repos {
     pub target_branch: Option<String>,
     pub ssh_key_path: Option<String>,
     pub branch_exclusions: Option<String>,
+    pub max_concurrent_jobs: Option<usize>,
+    pub cancel_superseded: Option<bool>,
+    pub cancel_superseded_branches: Option<String>,
+    pub quiet_period: Option<String>,
+}
+
+// A change check_repo_changes queued a build for
+#[derive(Debug, Clone)]
+pub struct QueuedChange {
+    pub queue_id: i64,
+    pub branch: String,
+    pub sha: String,
 }
 
 impl Default for Repo {
@@ -116,9 +148,13 @@ impl Default for Repo {
             work_dir: "".to_string(),
             last_sha: None,
             target_branch: "".to_string(),
-            triggered_branches: vec![],
             ssh_key_path: None,
             branch_exclusions: None,
+            previous_shas: HashMap::new(),
+            max_concurrent_jobs: None,
+            cancel_superseded: false,
+            cancel_superseded_branches: None,
+            quiet_period: None,
         }
     }
 }
@@ -137,9 +173,13 @@ impl Repo {
             work_dir,
             last_sha,
             target_branch,
-            triggered_branches: vec![],
             ssh_key_path: None,
             branch_exclusions: None,
+            previous_shas: HashMap::new(),
+            max_concurrent_jobs: None,
+            cancel_superseded: false,
+            cancel_superseded_branches: None,
+            quiet_period: None,
         }
     }
 
@@ -264,50 +304,58 @@ impl Repo {
         }
     }
 
-    pub fn check_repo_changes(&mut self) {
+    // Poll the tracked branches and queue a build for each that moved
+    pub fn check_repo_changes(&mut self, db: &DbConn) -> Vec<QueuedChange> {
         if self.target_branch.is_empty() {
             let git = SystemGitClient {};
             if let Err(e) = self.fetch_pull() {
                 eprintln!("Error during git fetch for {}: {}", self.path, e);
-                return;
+                return vec![];
             }
             let branches = git.remote_branches(&self.work_dir);
             let exclusions: Vec<String> = self.branch_exclusions.as_ref()
                 .map(|s| s.split(',').map(|s| s.trim().to_string()).collect())
                 .unwrap_or_default();
 
-            for branch in branches {
-                if exclusions.contains(&branch) {
-                    continue;
-                }
-                self.check_branch_changes(&branch);
-            }
+            branches
+                .iter()
+                .filter(|branch| !exclusions.contains(branch))
+                .filter_map(|branch| self.check_branch_changes(db, branch))
+                .collect()
         } else {
             let branch = self.target_branch.clone();
-            self.check_branch_changes(&branch);
+            self.check_branch_changes(db, &branch).into_iter().collect()
         }
     }
 
-    fn check_branch_changes(&mut self, branch: &str) {
+    fn check_branch_changes(&mut self, db: &DbConn, branch: &str) -> Option<QueuedChange> {
         if let Some(latest_sha) = self.git_latest_sha(branch) {
             // read last known SHA from DB (empty string if none)
-            let last_sha = self.get_sha_by_repo(branch);
+            let last_sha = self.get_sha_by_repo(db, branch);
 
             if last_sha.is_empty() {
                 // first-time initialization
-                self.set_sha_by_repo(branch, latest_sha.clone());
+                self.set_sha_by_repo(db, branch, latest_sha.clone());
                 if self.target_branch == branch || self.target_branch.is_empty() {
                     self.last_sha = Some(latest_sha);
                 }
-                return;
+                return None;
             }
 
             if last_sha != latest_sha {
-                // Persist the new SHA first
-                self.set_sha_by_repo(branch, latest_sha.clone());
-
-                // Mark branch as triggered
-                self.triggered_branches.push(branch.to_string());
+                // Queue the build before persisting the new SHA, so a restart in between
+                // detects the change again rather than losing it
+                let quiet_period = self.quiet_period();
+                let queue_id =
+                    match QueueEntry::enqueue(db, &self.path, branch, &latest_sha, quiet_period) {
+                        Ok((id, _)) => id,
+                        Err(e) => {
+                            eprintln!("Unable to queue {} [branch: {}]: {}", self.path, branch, e);
+                            return None;
+                        }
+                    };
+                self.set_sha_by_repo(db, branch, latest_sha.clone());
+                self.previous_shas.insert(branch.to_string(), last_sha);
                 if self.target_branch == branch || self.target_branch.is_empty() {
                     self.last_sha = Some(latest_sha.clone());
                 }
@@ -318,61 +366,165 @@ impl Repo {
                     "Change detected in repo: {} [branch: {}]\nNew SHA: {}",
                     self.path, branch, latest_sha
                 );
+                if let Some(quiet) = quiet_period {
+                    println!(
+                        "Building in {:?} unless the branch moves again (quiet_period)",
+                        quiet
+                    );
+                }
                 println!("========================================================");
+                return Some(QueuedChange {
+                    queue_id,
+                    branch: branch.to_string(),
+                    sha: latest_sha,
+                });
             }
         }
+        None
     }
 
-    // Check for changes in a repository and handle them
-    pub async fn check_repo_triggered(&mut self, tx_clone: Sender<String>) {
-        let branches = self.triggered_branches.clone();
-        self.triggered_branches.clear();
-
-        for branch in branches {
-            Job::update_start_time(self.path.clone(), branch.clone());
+    // Whether a newer commit on `branch` cancels its running build
+    pub fn cancels_superseded(&self, branch: &str) -> bool {
+        self.cancel_superseded
+            || self
+                .cancel_superseded_branches
+                .as_deref()
+                .is_some_and(|branches| branches.split(',').any(|b| b.trim() == branch))
+    }
 
-            // Ensure working copy is updated to the latest remote state for the branch
-            if let Err(e) = self.pull_branch(&branch) {
+    // Parsed quiet_period; an invalid or overlong value is reported and ignored
+    fn quiet_period(&self) -> Option<Duration> {
+        let quiet = self.quiet_period.as_deref()?;
+        match parse_duration(quiet) {
+            Ok(period) if period > MAX_QUIET_PERIOD => {
                 eprintln!(
-                    "Failed to update working tree for {} on {}: {}",
-                    self.path, branch, e
+                    "Invalid quiet_period '{}' for {}: longer than {:?}",
+                    quiet, self.path, MAX_QUIET_PERIOD
+                );
+                None
+            }
+            Ok(period) => Some(period),
+            Err(e) => {
+                eprintln!("Invalid quiet_period '{}' for {}: {}", quiet, self.path, e);
+                None
+            }
+        }
+    }
+
+    // Checkout for a job in `slot` of the worker pool: a git worktree next to the main clone.
+    // The clone itself belongs to the poller, which fetches into it and may re-clone it, and
+    // concurrent jobs of one repo must not share a working tree either. Runs git; call it off
+    // the async runtime.
+    pub fn job_work_dir(&self, slot: usize) -> Result<String, anyhow::Error> {
+        let dir = format!("{}@{}", self.work_dir.trim_end_matches('/'), slot + 1);
+        if !Path::new(&dir).join(".git").exists() {
+            // Forget worktrees whose directory was removed, e.g. by `phantom_ci reset`
+            let _ = Command::new("git")
+                .arg("-C")
+                .arg(&self.work_dir)
+                .args(["worktree", "prune"])
+                .output();
+            let out = Command::new("git")
+                .arg("-C")
+                .arg(&self.work_dir)
+                .args(["worktree", "add", "--detach"])
+                .arg(&dir)
+                .output()?;
+            if !out.status.success() {
+                anyhow::bail!(
+                    "git worktree add {} failed\nstderr: {}",
+                    dir,
+                    String::from_utf8_lossy(&out.stderr)
                 );
             }
+            println!("Created worktree {} for {}", dir, self.path);
+        }
+        Ok(dir)
+    }
 
-            // Mark job running and trigger workflow processing
-            Job::update_status(
-                self.path.clone(),
-                branch.clone(),
-                "running".to_string(),
+    // Run the workflow of a triggered branch in `work_dir` (see job_work_dir)
+    pub async fn run_triggered(
+        &self,
+        db: &DbConn,
+        branch: &str,
+        work_dir: &str,
+        tx_clone: Sender<String>,
+        cancel: CancelToken,
+    ) {
+        let mut job = self.clone();
+        job.work_dir = work_dir.to_string();
+        Job::update_start_time(db, &job.path, branch);
+
+        // Bring the worktree to the latest remote state of the branch; git runs on the
+        // blocking pool so it does not stall other jobs or the poller
+        let updated = tokio::task::spawn_blocking({
+            let (job, branch) = (job.clone(), branch.to_string());
+            move || job.checkout_detached(&branch)
+        })
+        .await
+        .unwrap_or_else(|e| Err(anyhow::anyhow!("checkout task failed: {}", e)));
+        if let Err(e) = updated {
+            eprintln!(
+                "Failed to update working tree for {} on {}: {}",
+                job.path, branch, e
             );
+        }
 
-            // Parse workflow file
-            let repo_name = self
-                .path
-                .rsplit('/')
-                .next()
-                .unwrap_or("")
-                .to_string();
-            if let Some(base) = default_repo_work_path(repo_name) {
-                let wp = format!("{}/workflow/{}.toml", base, branch);
-                let workflow_path = Path::new(&wp);
-                if workflow_path.exists() {
-                    if let Some(wp_str) = workflow_path.to_str() {
-                        // Temporarily set target_branch so parse_workflow uses the correct one if needed
-                        let old_target = self.target_branch.clone();
-                        self.target_branch = branch.clone();
-                        parse_workflow(wp_str, self.to_owned(), tx_clone.clone()).await;
-                        self.target_branch = old_target;
-                    } else {
-                        eprintln!("Invalid workflow path");
-                    }
-                } else {
-                    eprintln!("Workflow file not found at {}", wp);
-                }
-            } else {
-                eprintln!("Failed to determine default repo work path; skipping workflow parse");
-            }
+        // Mark job running and trigger workflow processing
+        Job::update_status(db, &job.path, branch, "running");
+
+        let wp = format!("{}/workflow/{}.toml", job.work_dir, branch);
+        if !Path::new(&wp).exists() {
+            eprintln!("Workflow file not found at {}", wp);
+            return;
         }
+        // Record the run against the triggered branch and the commit it is at
+        job.target_branch = branch.to_string();
+        job.last_sha = Some(job.get_sha_by_repo(db, branch));
+        parse_workflow(
+            &wp,
+            job,
+            "new commit",
+            db,
+            tx_clone,
+            RunOptions {
+                cancel,
+                ..Default::default()
+            },
+        )
+        .await;
+    }
+
+    fn checkout_detached(&self, branch: &str) -> Result<(), anyhow::Error> {
+        // Refs are shared with the main clone, so fetching here updates both
+        let fetch = Command::new("git")
+            .arg("-C")
+            .arg(&self.work_dir)
+            .args(["fetch", "origin", branch])
+            .output()?;
+        if !fetch.status.success() {
+            eprintln!(
+                "git fetch failed for {}/{}\nstderr: {}",
+                self.path,
+                branch,
+                String::from_utf8_lossy(&fetch.stderr)
+            );
+        }
+        let out = Command::new("git")
+            .arg("-C")
+            .arg(&self.work_dir)
+            .args(["checkout", "--force", "--detach"])
+            .arg(format!("origin/{}", branch))
+            .output()?;
+        if !out.status.success() {
+            anyhow::bail!(
+                "git checkout --detach origin/{} failed\nstderr: {}",
+                branch,
+                String::from_utf8_lossy(&out.stderr)
+            );
+        }
+        println!("Updated worktree {} for {} on branch {}", self.work_dir, self.path, branch);
+        Ok(())
     }
 
     pub fn git_latest_sha(&mut self, branch: &str) -> Option<String> {
@@ -495,68 +647,6 @@ impl Repo {
         Ok(())
     }
 
-    pub fn pull_branch(&mut self, branch: &str) -> Result<(), anyhow::Error> {
-        // Ensure the local branch exists and is set to track the remote branch
-        let checkout_output = Command::new("git")
-            .arg("-C")
-            .arg(&self.work_dir)
-            .arg("checkout")
-            .arg("-B")
-            .arg(branch)
-            .arg(format!("origin/{}", branch))
-            .output()?;
-        if !checkout_output.status.success() {
-            eprintln!(
-                "git checkout -B failed for {}/{}\nstderr: {}\nstdout: {}",
-                self.path,
-                branch,
-                String::from_utf8_lossy(&checkout_output.stderr),
-                String::from_utf8_lossy(&checkout_output.stdout)
-            );
-        }
-
-        // Try a fast-forward pull; if it fails, hard reset to remote
-        let pull_output = Command::new("git")
-            .arg("-C")
-            .arg(&self.work_dir)
-            .arg("pull")
-            .arg("--ff-only")
-            .arg("origin")
-            .arg(branch)
-            .output()?;
-        if !pull_output.status.success() {
-            eprintln!(
-                "git pull --ff-only failed for {}/{} (will try hard reset)\nstderr: {}\nstdout: {}",
-                self.path,
-                branch,
-                String::from_utf8_lossy(&pull_output.stderr),
-                String::from_utf8_lossy(&pull_output.stdout)
-            );
-
-            // Fallback: hard reset to the remote branch tip
-            let reset_output = Command::new("git")
-                .arg("-C")
-                .arg(&self.work_dir)
-                .arg("reset")
-                .arg("--hard")
-                .arg(format!("origin/{}", branch))
-                .output()?;
-            if !reset_output.status.success() {
-                let code = reset_output.status.code().unwrap_or(-1);
-                anyhow::bail!(
-                    "git reset --hard origin/{} failed (exit code {})\nstderr: {}\nstdout: {}",
-                    branch,
-                    code,
-                    String::from_utf8_lossy(&reset_output.stderr),
-                    String::from_utf8_lossy(&reset_output.stdout)
-                );
-            }
-        }
-
-        println!("Updated working copy for {} on branch {}", self.path, branch);
-        Ok(())
-    }
-
     pub fn write_repo_to_config(&mut self) {
         let name = self.path.rsplit('/').next().unwrap();
         let config_entry = format!(
@@ -677,22 +767,12 @@ impl Repo {
         head_branch
     }
 
-    fn get_sha_by_repo(&self, branch: &str) -> String {
-        let repo = String::from(&self.path);
-        let jobs = Job::get_jobs_by_repo(repo, branch.to_string());
-        let mut sha = String::new();
-        for job in jobs {
-            sha = job.sha;
-        }
-        sha
+    fn get_sha_by_repo(&self, db: &DbConn, branch: &str) -> String {
+        Job::get_sha(db, &self.path, branch)
     }
 
-    fn set_sha_by_repo(&self, branch: &str, latest_sha: String) {
-        Job::update_sha(
-            String::from(&self.path),
-            branch.to_string(),
-            latest_sha.clone(),
-        );
+    fn set_sha_by_repo(&self, db: &DbConn, branch: &str, latest_sha: String) {
+        Job::update_sha(db, &self.path, branch, &latest_sha);
     }
 }
 
@@ -716,12 +796,16 @@ pub fn load_repos_from_config(config_dir: &str) -> Vec<Repo> {
                         .to_owned()
                         .target_branch
                         .unwrap_or("".to_string()),
-                    triggered_branches: vec![],
                     ssh_key_path: r.1.ssh_key_path.clone().and_then(|s| {
                         let t = s.trim().to_string();
                         if t.is_empty() { None } else { Some(t) }
                     }),
                     branch_exclusions: r.1.branch_exclusions.clone(),
+                    previous_shas: HashMap::new(),
+                    max_concurrent_jobs: r.1.max_concurrent_jobs,
+                    cancel_superseded: r.1.cancel_superseded.unwrap_or(false),
+                    cancel_superseded_branches: r.1.cancel_superseded_branches.clone(),
+                    quiet_period: r.1.quiet_period.clone(),
                 })
             });
             repos
@@ -792,6 +876,7 @@ pub fn repo_work_dir(repo: &Repos) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::database::SqliteConnection;
 
     struct MockGitClient {
         present: Vec<String>,
@@ -817,9 +902,13 @@ mod tests {
             work_dir: "/tmp/phantom_ci-test".into(),
             last_sha: None,
             target_branch: "".into(),
-            triggered_branches: vec![],
             ssh_key_path: None,
             branch_exclusions: None,
+            previous_shas: HashMap::new(),
+            max_concurrent_jobs: None,
+            cancel_superseded: false,
+            cancel_superseded_branches: None,
+            quiet_period: None,
         }
     }
 
@@ -872,4 +961,91 @@ mod tests {
         assert_eq!(res.as_deref(), Some("main"));
         assert_eq!(repo.target_branch, "main");
     }
+
+    #[test]
+    fn cancel_superseded_per_branch() {
+        let mut repo = dummy_repo();
+        assert!(!repo.cancels_superseded("main"));
+        repo.cancel_superseded_branches = Some("main, staging".into());
+        assert!(repo.cancels_superseded("main"));
+        assert!(repo.cancels_superseded("staging"));
+        assert!(!repo.cancels_superseded("feature/x"));
+        repo.cancel_superseded = true;
+        assert!(repo.cancels_superseded("feature/x"));
+    }
+
+    #[test]
+    fn job_work_dir_is_never_the_clone() {
+        let root = std::env::temp_dir().join("phantom_ci-test-worktree");
+        let _ = fs::remove_dir_all(&root);
+        let clone = root.join("repo");
+        fs::create_dir_all(&clone).unwrap();
+        let git = |args: &[&str]| {
+            let out = Command::new("git").arg("-C").arg(&clone).args(args).output().unwrap();
+            assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        };
+        git(&["init", "-q"]);
+        git(&["-c", "user.name=ci", "-c", "user.email=ci@example.com", "commit", "-q", "--allow-empty", "-m", "init"]);
+
+        let mut repo = dummy_repo();
+        repo.work_dir = clone.to_string_lossy().to_string();
+        let first = repo.job_work_dir(0).unwrap();
+        assert_eq!(first, format!("{}@1", repo.work_dir));
+        assert!(Path::new(&first).join(".git").exists());
+        // Existing worktrees are reused
+        assert_eq!(repo.job_work_dir(0).unwrap(), first);
+        assert_eq!(repo.job_work_dir(1).unwrap(), format!("{}@2", repo.work_dir));
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn overlong_quiet_period_still_queues() {
+        let root = std::env::temp_dir().join("phantom_ci-test-quiet-period");
+        let _ = fs::remove_dir_all(&root);
+        let origin = root.join("origin");
+        fs::create_dir_all(&origin).unwrap();
+        let git = |dir: &Path, args: &[&str]| {
+            let out = Command::new("git").arg("-C").arg(dir).args(args).output().unwrap();
+            assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        };
+        let commit = |msg: &str| {
+            git(&origin, &["-c", "user.name=ci", "-c", "user.email=ci@example.com", "commit", "-q", "--allow-empty", "-m", msg]);
+        };
+        git(&origin, &["init", "-q", "-b", "main"]);
+        commit("init");
+        git(&root, &["clone", "-q", "origin", "clone"]);
+
+        let db = SqliteConnection::open_in_memory().unwrap().into_shared();
+        let mut repo = dummy_repo();
+        repo.path = origin.to_string_lossy().to_string();
+        repo.work_dir = root.join("clone").to_string_lossy().to_string();
+        repo.target_branch = "main".into();
+        repo.quiet_period = Some("100000000000d".into());
+        Job {
+            id: 0,
+            repo: repo.path.clone(),
+            status: "idle".to_string(),
+            priority: 0,
+            created_at: "".to_string(),
+            updated_at: "".to_string(),
+            start_time: "".to_string(),
+            finish_time: "".to_string(),
+            error_message: "".to_string(),
+            result: "".to_string(),
+            sha: "".to_string(),
+            target_branch: "main".to_string(),
+        }
+        .add_job(&db);
+        assert!(repo.quiet_period().is_none());
+        assert!(repo.check_repo_changes(&db).is_empty());
+
+        commit("change");
+        let changes = repo.check_repo_changes(&db);
+        assert_eq!(changes.len(), 1);
+        let ready = QueueEntry::get_ready(&db);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].sha, changes[0].sha);
+        assert_eq!(Job::get_sha(&db, &repo.path, "main"), changes[0].sha);
+        let _ = fs::remove_dir_all(&root);
+    }
 }
@@ -0,0 +1,141 @@
+use crate::util::{parse_duration, parse_size};
+use config::{Config, File, FileFormat};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+// Wait for running jobs on SIGTERM/SIGINT unless Config.toml sets shutdown_timeout
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// Global settings from <config dir>/Config.toml; per-repo settings live in Repo.toml
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    // Triggered runs executing at once across all repos
+    pub max_concurrent_jobs: Option<usize>,
+    // How long a stopping daemon waits for running jobs before interrupting them, e.g. "5m"
+    pub shutdown_timeout: Option<String>,
+    // Build again what a stopped or crashed daemon left unfinished (default true)
+    pub requeue_interrupted: Option<bool>,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+}
+
+impl Settings {
+    pub fn shutdown_timeout(&self) -> Result<Duration, String> {
+        match &self.shutdown_timeout {
+            Some(timeout) => {
+                parse_duration(timeout).map_err(|e| format!("invalid shutdown_timeout: {}", e))
+            }
+            None => Ok(DEFAULT_SHUTDOWN_TIMEOUT),
+        }
+    }
+}
+
+// [retention] as written in Config.toml; every limit is optional
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+    // Delete runs and log rows older than this, e.g. "30d"
+    pub max_age: Option<String>,
+    // Runs kept per repo/branch
+    pub max_runs: Option<usize>,
+    // Budget for the database plus step log files, e.g. "2GB"
+    pub max_size: Option<String>,
+}
+
+// Parsed retention limits
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub max_runs: Option<usize>,
+    pub max_size: Option<u64>,
+}
+
+impl RetentionConfig {
+    pub fn resolve(&self) -> Result<Retention, String> {
+        let max_age = match &self.max_age {
+            Some(age) => Some(
+                parse_duration(age).map_err(|e| format!("invalid retention.max_age: {}", e))?,
+            ),
+            None => None,
+        };
+        let max_size = match &self.max_size {
+            Some(size) => {
+                Some(parse_size(size).map_err(|e| format!("invalid retention.max_size: {}", e))?)
+            }
+            None => None,
+        };
+        Ok(Retention {
+            max_age,
+            max_runs: self.max_runs,
+            max_size,
+        })
+    }
+}
+
+impl Retention {
+    pub fn is_empty(&self) -> bool {
+        *self == Retention::default()
+    }
+}
+
+pub fn settings_path(config_dir: &str) -> String {
+    format!("{}Config.toml", config_dir)
+}
+
+// Load Config.toml; a missing file means the defaults
+pub fn load_settings(config_dir: &str) -> Result<Settings, String> {
+    let path = settings_path(config_dir);
+    if !Path::new(&path).exists() {
+        return Ok(Settings::default());
+    }
+    Config::builder()
+        .add_source(File::new(&path, FileFormat::Toml))
+        .build()
+        .and_then(|c| c.try_deserialize::<Settings>())
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn create_default_settings(path: &str) {
+    let default_settings = r#"
+## Global settings
+##max_concurrent_jobs = 4   # Optional; triggered runs executing at once across all repos
+## Re-run `phantom_ci configure service` after changing shutdown_timeout
+##shutdown_timeout = "5m"   # Optional; on SIGTERM/SIGINT wait this long for running jobs
+##requeue_interrupted = true  # Optional; build again what a stopped daemon left unfinished
+
+##[retention]
+##max_age = "30d"    # Optional; delete runs and job logs older than this
+##max_runs = 100     # Optional; runs kept per repo/branch
+##max_size = "2GB"   # Optional; budget for the database plus step log files
+
+"#;
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        let _ = file.write_all(default_settings.as_ref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_retention() {
+        let config = RetentionConfig {
+            max_age: Some("30d".to_string()),
+            max_runs: Some(10),
+            max_size: Some("1.5GB".to_string()),
+        };
+        let retention = config.resolve().unwrap();
+        assert_eq!(retention.max_age, Some(Duration::from_secs(30 * 24 * 3600)));
+        assert_eq!(retention.max_size, Some(1536 * 1024 * 1024));
+        assert!(RetentionConfig::default().resolve().unwrap().is_empty());
+        let config = RetentionConfig {
+            max_size: Some("lots".to_string()),
+            ..Default::default()
+        };
+        assert!(config.resolve().is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+
+// Exclusive flock on <config dir>/phantom_ci.lock, held for as long as the daemon runs so two
+// daemons never share a config dir and database. The kernel drops it when the process exits,
+// however that happens.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+pub fn lock_path(config_dir: &str) -> String {
+    format!("{}phantom_ci.lock", config_dir)
+}
+
+impl InstanceLock {
+    pub fn acquire(path: &str) -> Result<InstanceLock, String> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .map_err(|e| format!("unable to open lock file {}: {}", path, e))?;
+        let locked = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if locked != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                let pid = std::fs::read_to_string(path).unwrap_or_default();
+                return Err(format!(
+                    "another phantom_ci daemon is running (pid {}, lock {})",
+                    pid.trim(),
+                    path
+                ));
+            }
+            return Err(format!("unable to lock {}: {}", path, err));
+        }
+        // Record who holds it, for the message above
+        let _ = file.set_len(0);
+        let _ = write!(file, "{}", std::process::id());
+        Ok(InstanceLock { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_lock_fails() {
+        let path = std::env::temp_dir().join("phantom_ci-test.lock");
+        let path = path.to_string_lossy().to_string();
+        let held = InstanceLock::acquire(&path).unwrap();
+        // flock locks belong to the open file, so a second open conflicts even in one process
+        let err = InstanceLock::acquire(&path).unwrap_err();
+        assert!(err.contains(&std::process::id().to_string()));
+        drop(held);
+        assert!(InstanceLock::acquire(&path).is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,9 +1,12 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
 use std::env::consts::OS;
 use std::path::Path;
 use std::process::exit;
+use std::time::Duration;
 use std::{fs, thread};
 use tokio::process;
 
+pub mod lock;
 pub mod service;
 
 pub fn default_repo_work_path_remove_cache_data() {
@@ -186,3 +189,156 @@ pub fn default_config_path() -> Option<String> {
         panic!("unable to determine user name");
     }
 }
+
+// Parse durations such as "90", "30s", "10m", "1h30m" or "500ms" (bare numbers are seconds)
+pub fn parse_duration(input: &str) -> Result<Duration, anyhow::Error> {
+    let s = input.trim();
+    if s.is_empty() {
+        anyhow::bail!("empty duration");
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits == 0 {
+            anyhow::bail!("invalid duration '{}'", input);
+        }
+        let value = rest[..digits].parse::<u64>()?;
+        rest = &rest[digits..];
+        let unit_len = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_alphabetic()).len();
+        let unit = &rest[..unit_len];
+        rest = &rest[unit_len..];
+        let part = match unit {
+            "ms" => Some(Duration::from_millis(value)),
+            "s" => Some(Duration::from_secs(value)),
+            "m" => value.checked_mul(60).map(Duration::from_secs),
+            "h" => value.checked_mul(60 * 60).map(Duration::from_secs),
+            "d" => value.checked_mul(60 * 60 * 24).map(Duration::from_secs),
+            _ => anyhow::bail!("invalid duration unit '{}' in '{}'", unit, input),
+        };
+        total = part
+            .and_then(|part| total.checked_add(part))
+            .ok_or_else(|| anyhow::anyhow!("duration '{}' is too large", input))?;
+    }
+    Ok(total)
+}
+
+// Parse sizes such as "500MB", "1.5GB" or "2048" (bytes); units are powers of 1024
+pub fn parse_size(input: &str) -> Result<u64, anyhow::Error> {
+    let s = input.trim();
+    let digits = s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.').len();
+    let value = s[..digits]
+        .parse::<f64>()
+        .map_err(|_| anyhow::anyhow!("invalid size '{}'", input))?;
+    let multiplier: u64 = match s[digits..].trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        unit => anyhow::bail!("invalid size unit '{}' in '{}'", unit, input),
+    };
+    Ok((value * multiplier as f64) as u64)
+}
+
+// "1.5 MB" style size for messages
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// Parse a point in time: RFC 3339, "YYYY-MM-DD HH:MM:SS" or "YYYY-MM-DD" in local time,
+// or an age such as "2h" (see parse_duration) counted back from now
+pub fn parse_time(input: &str) -> Result<DateTime<Local>, anyhow::Error> {
+    let s = input.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Local));
+    }
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S"))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        });
+    if let Some(naive) = naive {
+        return Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .ok_or_else(|| anyhow::anyhow!("invalid local time '{}'", input));
+    }
+    match parse_duration(s) {
+        Ok(age) => chrono::Duration::from_std(age)
+            .ok()
+            .and_then(|age| Local::now().checked_sub_signed(age))
+            .ok_or_else(|| anyhow::anyhow!("age '{}' is too large", input)),
+        Err(_) => anyhow::bail!("invalid time '{}'", input),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_duration, parse_size, parse_time};
+    use chrono::{Local, TimeZone};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("18446744073709551615d").is_err());
+        assert!(parse_duration("18446744073709551615s1s").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("2048").unwrap(), 2048);
+        assert_eq!(parse_size("500MB").unwrap(), 500 * 1024 * 1024);
+        assert_eq!(parse_size("1.5 GB").unwrap(), 1536 * 1024 * 1024);
+        assert_eq!(parse_size("10k").unwrap(), 10240);
+        assert!(parse_size("GB").is_err());
+        assert!(parse_size("10 parsecs").is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(
+            parse_time("2024-01-01T10:00:00+00:00").unwrap(),
+            parse_time("2024-01-01T12:00:00+02:00").unwrap()
+        );
+        assert_eq!(
+            parse_time("2024-01-01").unwrap(),
+            Local.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            parse_time("2024-01-01 08:30:00").unwrap(),
+            Local.with_ymd_and_hms(2024, 1, 1, 8, 30, 0).unwrap()
+        );
+        let age = Local::now() - parse_time("2h").unwrap();
+        assert!((age.num_seconds() - 7200).abs() <= 1, "{:?}", age);
+        assert!(parse_time("yesterday").is_err());
+        assert!(parse_time("100000000d").is_err());
+        assert!(parse_time("1000000000d").is_err());
+    }
+}
@@ -1,11 +1,16 @@
+use std::time::Duration;
 use std::{fs, process};
 
+// Extra time systemd allows on top of the daemon's own shutdown before it kills what is left
+const STOP_MARGIN: Duration = Duration::from_secs(30);
+
 fn default_systemd_service_dir(f: String) -> String {
     format!("/usr/lib/systemd/system/{}.service", f)
 }
 
-fn default_systemd_service_file() -> &'static str {
-    r#"
+fn default_systemd_service_file(stop_timeout: Duration) -> String {
+    format!(
+        r#"
 [Unit]
 Description=phantom_ci
 After=network.target
@@ -17,13 +22,20 @@ Type=simple
 RemainAfterExit=no
 Restart=always
 ExecStart=/root/.cargo/bin/phantom_ci
+# SIGTERM only the daemon, which stops its steps itself; TimeoutStopSec follows shutdown_timeout
+# in Config.toml, so run `phantom_ci configure service` again after changing it
+KillMode=mixed
+TimeoutStopSec={}
 
 [Install]
 WantedBy=default.target
-"#
+"#,
+        (stop_timeout + STOP_MARGIN).as_secs()
+    )
 }
 
-pub fn configure_systemd() {
+// Install the unit; `stop_timeout` is how long the daemon may take to shut down gracefully
+pub fn configure_systemd(stop_timeout: Duration) {
     let _ = process::Command::new("systemctl")
         .arg("stop")
         .arg("phantom_ci")
@@ -32,9 +44,20 @@ pub fn configure_systemd() {
     let service_file = default_systemd_service_dir("phantom_ci".to_string());
     println!("installing service.. {}", &service_file);
     let _ = fs::remove_file(&service_file);
-    if let Err(e) = fs::write(&service_file, default_systemd_service_file()) {
+    if let Err(e) = fs::write(&service_file, default_systemd_service_file(stop_timeout)) {
         println!("unable to install {}: {}", &service_file, e);
     }
 
     println!("\nservice installed\nplease run:\nsystemctl daemon-reload\nsystemctl enable phantom_ci\nsystemctl start phantom_ci");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stop_timeout_follows_shutdown() {
+        let unit = default_systemd_service_file(Duration::from_secs(30 * 60 + 30));
+        assert!(unit.contains("\nTimeoutStopSec=1860\n"), "{}", unit);
+    }
+}